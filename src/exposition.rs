@@ -0,0 +1,408 @@
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use serde::de::{Error as SerdeDeError, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+
+const MAX_BUCKETS: usize = 64;
+
+/// Label set of a series, keeps the insertion order like `tacho::Scope`.
+#[derive(Debug, Clone, Default)]
+pub struct Labels {
+    inner: Vec<(&'static str, String)>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Labels::default()
+    }
+
+    pub fn labeled<D: fmt::Display>(mut self, key: &'static str, value: D) -> Self {
+        let value = value.to_string();
+        match self.inner.iter_mut().find(|&&mut (k, _)| k == key) {
+            Some(label) => label.1 = value,
+            None => self.inner.push((key, value)),
+        }
+        self
+    }
+}
+
+impl fmt::Display for Labels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.inner.is_empty() {
+            return Ok(());
+        }
+
+        write!(f, "{{")?;
+        for (i, &(key, ref value)) in self.inner.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}=\"", key)?;
+            for c in value.chars() {
+                match c {
+                    '\\' => write!(f, "\\\\")?,
+                    '"' => write!(f, "\\\"")?,
+                    '\n' => write!(f, "\\n")?,
+                    c => write!(f, "{}", c)?,
+                }
+            }
+            write!(f, "\"")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+/// Default histogram buckets for round-trip times in seconds.
+static DEFAULT_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Sorted upper bounds of histogram buckets, parsed from a comma separated list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buckets(Vec<f64>);
+
+impl Default for Buckets {
+    fn default() -> Self {
+        Buckets(DEFAULT_BUCKETS.to_vec())
+    }
+}
+
+impl Deref for Buckets {
+    type Target = [f64];
+
+    fn deref(&self) -> &<Self as Deref>::Target {
+        &self.0
+    }
+}
+
+impl fmt::Display for Buckets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, bound) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", bound)?;
+        }
+        Ok(())
+    }
+}
+
+impl Buckets {
+    fn new(bounds: Vec<f64>) -> Result<Self, String> {
+        if bounds.is_empty() {
+            return Err("at least one bucket is required".to_string());
+        }
+        for (i, &bound) in bounds.iter().enumerate() {
+            if !bound.is_finite() {
+                return Err(format!("'{}' is not valid bucket bound", bound));
+            }
+            if i > 0 && bound <= bounds[i - 1] {
+                return Err("buckets must be in increasing order".to_string());
+            }
+        }
+
+        if bounds.len() > MAX_BUCKETS {
+            return Err(format!("too many buckets, maximum is {}", MAX_BUCKETS));
+        }
+        Ok(Buckets(bounds))
+    }
+}
+
+impl FromStr for Buckets {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        let bounds = s
+            .split(',')
+            .map(|bound| {
+                f64::from_str(bound.trim())
+                    .map_err(|_| format!("'{}' is not valid bucket bound", bound))
+            })
+            .collect::<Result<Vec<f64>, String>>()?;
+        Buckets::new(bounds)
+    }
+}
+
+struct BucketsVisitor;
+
+impl<'de> Visitor<'de> for BucketsVisitor {
+    type Value = Buckets;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a comma separated string or an array of bucket bounds")
+    }
+
+    fn visit_str<E: SerdeDeError>(self, value: &str) -> Result<Self::Value, E> {
+        Buckets::from_str(value).map_err(E::custom)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bounds = Vec::new();
+        while let Some(bound) = seq.next_element()? {
+            bounds.push(bound);
+        }
+        Buckets::new(bounds).map_err(A::Error::custom)
+    }
+}
+
+/// Accepts both a comma separated string (query parameters and environment)
+/// and an array of numbers (config file).
+impl<'de> Deserialize<'de> for Buckets {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(BucketsVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Kind::Counter => write!(f, "counter"),
+            &Kind::Gauge => write!(f, "gauge"),
+            &Kind::Histogram => write!(f, "histogram"),
+        }
+    }
+}
+
+struct Sample {
+    suffix: &'static str,
+    labels: Labels,
+    value: f64,
+}
+
+/// Accumulated observations, `counts` are per bucket, not cumulative.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &[f64]) -> Self {
+        Histogram {
+            bounds: buckets.to_vec(),
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        if let Some(index) = self.bounds.iter().position(|&bound| value <= bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    samples: Vec<Sample>,
+}
+
+/// Prometheus text format writer with floating-point values.
+#[derive(Default)]
+pub struct Exposition {
+    families: Vec<Family>,
+}
+
+impl Exposition {
+    pub fn new() -> Self {
+        Exposition::default()
+    }
+
+    pub fn gauge(&mut self, name: &'static str, help: &'static str, labels: &Labels, value: f64) {
+        self.family(name, help, Kind::Gauge).samples.push(Sample {
+            suffix: "",
+            labels: labels.clone(),
+            value,
+        });
+    }
+
+    pub fn counter(&mut self, name: &'static str, help: &'static str, labels: &Labels, value: f64) {
+        self.family(name, help, Kind::Counter).samples.push(Sample {
+            suffix: "",
+            labels: labels.clone(),
+            value,
+        });
+    }
+
+    /// Adds cumulative `_bucket` series for every upper bound in `buckets`
+    /// (which must be sorted) and `+Inf`, plus `_sum` and `_count`.
+    pub fn histogram(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &Labels,
+        buckets: &[f64],
+        values: &[f64],
+    ) {
+        let mut histogram = Histogram::new(buckets);
+        for &value in values {
+            histogram.observe(value);
+        }
+        self.histogram_from(name, help, labels, &histogram);
+    }
+
+    pub fn histogram_from(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &Labels,
+        histogram: &Histogram,
+    ) {
+        let family = self.family(name, help, Kind::Histogram);
+
+        let mut cumulative = 0;
+        for (&bound, &count) in histogram.bounds.iter().zip(histogram.counts.iter()) {
+            cumulative += count;
+            family.samples.push(Sample {
+                suffix: "_bucket",
+                labels: labels.clone().labeled("le", Value(bound)),
+                value: cumulative as f64,
+            });
+        }
+        family.samples.push(Sample {
+            suffix: "_bucket",
+            labels: labels.clone().labeled("le", Value(::std::f64::INFINITY)),
+            value: histogram.count as f64,
+        });
+        family.samples.push(Sample {
+            suffix: "_sum",
+            labels: labels.clone(),
+            value: histogram.sum,
+        });
+        family.samples.push(Sample {
+            suffix: "_count",
+            labels: labels.clone(),
+            value: histogram.count as f64,
+        });
+    }
+
+    fn family(&mut self, name: &'static str, help: &'static str, kind: Kind) -> &mut Family {
+        match self.families.iter().position(|family| family.name == name) {
+            Some(index) => &mut self.families[index],
+            None => {
+                self.families.push(Family {
+                    name,
+                    help,
+                    kind,
+                    samples: Vec::new(),
+                });
+                self.families.last_mut().expect("family")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Exposition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for family in &self.families {
+            writeln!(f, "# HELP {} {}", family.name, family.help)?;
+            writeln!(f, "# TYPE {} {}", family.name, family.kind)?;
+            for sample in &family.samples {
+                writeln!(
+                    f,
+                    "{}{}{} {}",
+                    family.name,
+                    sample.suffix,
+                    sample.labels,
+                    Value(sample.value)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Value(f64);
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_nan() {
+            write!(f, "NaN")
+        } else if self.0.is_infinite() && self.0 > 0.0 {
+            write!(f, "+Inf")
+        } else if self.0.is_infinite() {
+            write!(f, "-Inf")
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{Buckets, Exposition, Labels};
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(
+            Buckets::from_str("0.01, 0.1,1").map(|b| b.to_vec()),
+            Ok(vec![0.01, 0.1, 1.0])
+        );
+        assert!(Buckets::from_str("0.1,0.01").is_err());
+        assert!(Buckets::from_str("0.1,inf").is_err());
+        assert!(Buckets::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_gauge() {
+        let mut exposition = Exposition::new();
+        let labels = Labels::new()
+            .labeled("target", "example.com")
+            .labeled("error", "say \"hi\"");
+        exposition.gauge("ping_rtt_min_seconds", "Minimal RTT", &labels, 0.0125);
+        exposition.gauge("ping_rtt_min_seconds", "Minimal RTT", &Labels::new(), 1.0);
+
+        assert_eq!(
+            exposition.to_string(),
+            "# HELP ping_rtt_min_seconds Minimal RTT\n\
+             # TYPE ping_rtt_min_seconds gauge\n\
+             ping_rtt_min_seconds{target=\"example.com\",error=\"say \\\"hi\\\"\"} 0.0125\n\
+             ping_rtt_min_seconds 1\n"
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut exposition = Exposition::new();
+        let labels = Labels::new().labeled("target", "example.com");
+        exposition.histogram(
+            "ping_rtt_seconds",
+            "RTT",
+            &labels,
+            &[0.01, 0.1],
+            &[0.005, 0.05, 0.5],
+        );
+
+        assert_eq!(
+            exposition.to_string(),
+            "# HELP ping_rtt_seconds RTT\n\
+             # TYPE ping_rtt_seconds histogram\n\
+             ping_rtt_seconds_bucket{target=\"example.com\",le=\"0.01\"} 1\n\
+             ping_rtt_seconds_bucket{target=\"example.com\",le=\"0.1\"} 2\n\
+             ping_rtt_seconds_bucket{target=\"example.com\",le=\"+Inf\"} 3\n\
+             ping_rtt_seconds_sum{target=\"example.com\"} 0.555\n\
+             ping_rtt_seconds_count{target=\"example.com\"} 3\n"
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use trust_dns_resolver::Name;
+
+use utils::{fqdn, Protocol};
+
+/// Static addresses of names, consulted before DNS.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hosts {
+    inner: HashMap<Name, Vec<IpAddr>>,
+}
+
+impl Hosts {
+    /// Parses the hosts file format: an address followed by its names on every line,
+    /// `#` starts a comment. Invalid lines are skipped, like the system resolver does.
+    pub fn parse(content: &str) -> Self {
+        let mut hosts = Hosts::default();
+        for line in content.lines() {
+            let line = match line.find('#') {
+                Some(index) => &line[..index],
+                None => line,
+            };
+            let mut fields = line.split_whitespace();
+            let addr = match fields.next().map(IpAddr::from_str) {
+                Some(Ok(addr)) => addr,
+                _ => continue,
+            };
+            for name in fields.filter_map(|name| Name::from_str(name).ok()) {
+                hosts.inner.entry(fqdn(&name)).or_default().push(addr);
+            }
+        }
+        hosts
+    }
+
+    /// Replaces addresses of the name.
+    pub fn insert(&mut self, name: &Name, addrs: Vec<IpAddr>) {
+        self.inner.insert(fqdn(name), addrs);
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns addresses of the protocol, `None` if there are none so the name is resolved with DNS.
+    pub fn get(&self, name: &Name, protocol: Protocol) -> Option<Vec<IpAddr>> {
+        let addrs: Vec<IpAddr> = self
+            .inner
+            .get(&fqdn(name))?
+            .iter()
+            .cloned()
+            .filter(|addr| match (protocol, addr) {
+                (Protocol::V4, &IpAddr::V4(_)) | (Protocol::V6, &IpAddr::V6(_)) => true,
+                _ => false,
+            })
+            .collect();
+        if addrs.is_empty() {
+            None
+        } else {
+            Some(addrs)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use trust_dns_resolver::Name;
+
+    use super::Hosts;
+    use utils::Protocol;
+
+    #[test]
+    fn test_hosts() {
+        let mut hosts = Hosts::parse(
+            "# comment\n\
+             10.0.0.1 db.internal db # primary\n\
+             fd00::1  DB.internal\n\
+             invalid  web.internal\n\
+             10.0.0.2 web.internal\n",
+        );
+        let name = |name| Name::from_str(name).expect("name");
+        let addr = |addr| IpAddr::from_str(addr).expect("addr");
+
+        assert_eq!(hosts.len(), 3);
+        assert_eq!(
+            hosts.get(&name("db.internal."), Protocol::V4),
+            Some(vec![addr("10.0.0.1")])
+        );
+        assert_eq!(
+            hosts.get(&name("db.internal"), Protocol::V6),
+            Some(vec![addr("fd00::1")])
+        );
+        assert_eq!(hosts.get(&name("db"), Protocol::V6), None);
+        assert_eq!(
+            hosts.get(&name("web.internal"), Protocol::V4),
+            Some(vec![addr("10.0.0.2")])
+        );
+        assert_eq!(hosts.get(&name("example.com"), Protocol::V4), None);
+
+        hosts.insert(&name("db"), vec![addr("10.0.0.3")]);
+        assert_eq!(
+            hosts.get(&name("db"), Protocol::V4),
+            Some(vec![addr("10.0.0.3")])
+        );
+    }
+}
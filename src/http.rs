@@ -1,27 +1,47 @@
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 
-use futures::{future, Future};
+use futures::{future, stream, Future, Stream};
 use hyper::service::{NewService, Service};
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::de::DeserializeOwned;
 use serde_urlencoded;
 use tacho;
+use trust_dns_resolver::Name;
 
+use exposition::{Buckets, Exposition, Labels};
+use icmp;
 use metrics::{METRICS, REPORTER};
-use pinger::{Pinger, Report};
-use settings::Settings;
-use utils::{boxed, NameOrIpAddr, Protocol};
+use monitor::Monitor;
+use pinger::{Hop, Params, PathMtu, Pinger, Probe, Report};
+use reload::Reloader;
+use resolver::{self, Answer, CacheStatus, Upstream};
+use settings::{Module, Settings, SharedSettings};
+use socket::{Interface, Source};
+use stats::rtt_stats;
+use utils::{boxed, Addresses, List, Mode, NameOrIpAddr, Protocol, ProtocolChoice};
 
 lazy_static! {
     static ref HTTP_PING: tacho::Counter = METRICS.counter("http_ping", "Number of /ping requests");
+    static ref HTTP_PING_MANY: tacho::Counter =
+        METRICS.counter("http_ping_many", "Number of /ping_many requests");
+    static ref HTTP_TRACEROUTE: tacho::Counter =
+        METRICS.counter("http_traceroute", "Number of /traceroute requests");
+    static ref HTTP_PMTU: tacho::Counter = METRICS.counter("http_pmtu", "Number of /pmtu requests");
 }
 
 pub fn init() {
     ::lazy_static::initialize(&HTTP_PING);
+    ::lazy_static::initialize(&HTTP_PING_MANY);
+    ::lazy_static::initialize(&HTTP_TRACEROUTE);
+    ::lazy_static::initialize(&HTTP_PMTU);
 }
 
 struct NewApp {
-    settings: Settings,
+    settings: SharedSettings,
     pinger: Pinger,
+    monitor: Monitor,
+    reloader: Reloader,
 }
 
 impl NewService for NewApp {
@@ -36,28 +56,353 @@ impl NewService for NewApp {
         future::ok(App {
             settings: self.settings.clone(),
             pinger: self.pinger.clone(),
+            monitor: self.monitor.clone(),
+            reloader: self.reloader.clone(),
         })
     }
 }
 
 enum RequestType {
     Ping,
+    PingMany,
+    Traceroute,
+    Pmtu,
+    Targets,
     Metrics,
+    Reload,
     Unknown,
 }
 
 #[derive(Debug, Deserialize)]
 struct PingRequest {
     target: NameOrIpAddr,
+}
+
+#[derive(Debug, Deserialize)]
+struct PingManyRequest {
+    targets: List<NameOrIpAddr>,
+}
+
+/// Probe parameters shared by `/ping` and `/ping_many`, parsed from the same query.
+#[derive(Debug, Default, Deserialize)]
+struct PingOptions {
+    module: Option<String>,
+    protocol: Option<ProtocolChoice>,
+    resolver: Option<Upstream>,
+    addresses: Option<Addresses>,
+    mode: Option<Mode>,
+    port: Option<u16>,
+    size: Option<usize>,
+    df: Option<bool>,
+    ttl: Option<u32>,
+    tos: Option<u8>,
+    dscp: Option<u8>,
+    source: Option<IpAddr>,
+    interface: Option<Interface>,
+    count: Option<usize>,
+    ping_timeout: Option<u64>,
+    resolve_timeout: Option<u64>,
+    buckets: Option<Buckets>,
+    ptr: Option<bool>,
+    cache: Option<bool>,
+}
+
+impl PingOptions {
+    /// Fills parameters missing from the query with the module values.
+    ///
+    /// `tos` and `dscp` set the same bits, so the request's one replaces both module values.
+    fn with_module(self, module: &Module) -> Self {
+        let (tos, dscp) = if self.tos.is_some() || self.dscp.is_some() {
+            (self.tos, self.dscp)
+        } else {
+            (module.tos, module.dscp)
+        };
+        PingOptions {
+            protocol: self.protocol.or(module.protocol),
+            resolver: self.resolver.or_else(|| module.resolver.clone()),
+            addresses: self.addresses.or(module.addresses),
+            mode: self.mode.or(module.mode),
+            port: self.port.or(module.port),
+            size: self.size.or(module.size),
+            df: self.df.or(module.df),
+            ttl: self.ttl.or(module.ttl),
+            tos,
+            dscp,
+            source: self.source.or(module.source),
+            interface: self.interface.or(module.interface),
+            count: self.count.or(module.count),
+            ping_timeout: self.ping_timeout.or(module.ping_timeout),
+            resolve_timeout: self.resolve_timeout.or(module.resolve_timeout),
+            buckets: self.buckets.or_else(|| module.buckets.clone()),
+            ptr: self.ptr.or(module.ptr),
+            cache: self.cache.or(module.cache),
+            ..self
+        }
+    }
+}
+
+/// Validated probe parameters with defaults applied.
+#[derive(Debug, Clone)]
+struct PingParams {
+    module: Option<String>,
+    protocol: ProtocolChoice,
+    resolver: Option<Upstream>,
+    addresses: Addresses,
+    mode: Mode,
+    probe: Probe,
+    /// Requested DSCP, the series are labelled by it rather than by the resulting TOS.
+    dscp: Option<u8>,
+    source: Source,
+    /// Requested or module source address, it must match a single requested protocol.
+    requested_source: Option<IpAddr>,
+    count: usize,
+    ping_timeout: u64,
+    resolve_timeout: u64,
+    buckets: Buckets,
+    ptr: bool,
+    cache: bool,
+}
+
+/// Report over a single protocol with names of the pinged addresses, looked up with `ptr=true`.
+type Probed = (Labels, Report, BTreeMap<IpAddr, Name>);
+
+impl PingParams {
+    fn new(options: PingOptions, settings: &Settings) -> Result<Self, &'static str> {
+        let module = match options.module {
+            Some(ref name) => match settings.modules.get(name) {
+                Some(module) => Some(module.clone()),
+                None => return Err("Unknown module"),
+            },
+            None => None,
+        };
+        let options = match module {
+            Some(ref module) => options.with_module(module),
+            None => options,
+        };
+
+        if let Some(body) = check_options(&options, settings, module.as_ref()) {
+            return Err(body);
+        }
+
+        let count = options.count.unwrap_or(settings.count);
+        let ping_timeout = options.ping_timeout.unwrap_or(settings.ping_timeout);
+        let resolve_timeout = options.resolve_timeout.unwrap_or(settings.resolve_timeout);
+        let mode = options.mode.unwrap_or(Mode::Icmp);
+
+        let probe = match (mode, options.port) {
+            (Mode::Tcp, Some(port)) => Probe::Tcp { port },
+            (Mode::Udp, Some(port)) => Probe::Udp { port },
+            _ => Probe::Icmp(icmp::Options {
+                ttl: options.ttl,
+                size: options.size,
+                df: options.df.unwrap_or(false),
+                tos: options.tos.or(options.dscp.map(|dscp| dscp << 2)),
+                ..icmp::Options::default()
+            }),
+        };
+
+        Ok(PingParams {
+            module: options.module,
+            protocol: options
+                .protocol
+                .unwrap_or(ProtocolChoice::Single(settings.protocol)),
+            resolver: options.resolver,
+            addresses: options.addresses.unwrap_or(Addresses::Random),
+            mode,
+            probe,
+            dscp: options.dscp,
+            source: source(settings, options.source, options.interface),
+            requested_source: options.source,
+            count,
+            ping_timeout,
+            resolve_timeout,
+            buckets: options.buckets.unwrap_or_else(|| settings.buckets.clone()),
+            ptr: options.ptr.unwrap_or(false),
+            cache: options.cache.unwrap_or(true),
+        })
+    }
+
+    fn params(&self) -> Params {
+        Params {
+            resolver: self.resolver.clone(),
+            cache: self.cache,
+            addresses: self.addresses,
+            source: self.source,
+            count: self.count,
+            resolve_timeout: self.resolve_timeout,
+            timeout: self.ping_timeout,
+        }
+    }
+
+    /// Pings the target over every requested protocol, IP addresses are pinged over their own.
+    fn ping(
+        &self,
+        pinger: &Pinger,
+        name: NameOrIpAddr,
+    ) -> Box<Future<Item = Vec<Probed>, Error = (StatusCode, Body)> + Send> {
+        let choice = match name.ip_protocol() {
+            Some(protocol) => ProtocolChoice::Single(protocol),
+            None => self.protocol,
+        };
+
+        if let ProtocolChoice::Single(protocol) = choice {
+            if let Some(body) = check_source_protocol(self.requested_source, protocol) {
+                return boxed(future::err((StatusCode::BAD_REQUEST, Body::from(body))));
+            }
+        }
+
+        match choice {
+            ProtocolChoice::Single(protocol) => boxed(
+                self.ping_with(pinger, name, protocol)
+                    .map(|result| vec![result]),
+            ),
+            ProtocolChoice::Both => boxed(
+                self.ping_with(pinger, name.clone(), Protocol::V4)
+                    .join(self.ping_with(pinger, name, Protocol::V6))
+                    .map(|(v4, v6)| vec![v4, v6]),
+            ),
+            ProtocolChoice::Auto => {
+                let params = self.clone();
+                let pinger = pinger.clone();
+                let future = pinger
+                    .happy_eyeballs(name.clone(), self.probe, &self.params())
+                    .map_err(internal_error)
+                    .and_then(move |(protocol, report)| {
+                        params.probed(&pinger, name, protocol, report)
+                    })
+                    .map(|result| vec![result]);
+                boxed(future)
+            }
+        }
+    }
+
+    /// Series over a single protocol, sent from `source`.
+    fn labels(&self, name: &NameOrIpAddr, protocol: Protocol, source: &Source) -> Labels {
+        let mut labels = Labels::new().labeled("target", name);
+        if let Some(ref module) = self.module {
+            labels = labels.labeled("module", module);
+        }
+        let mut labels = labels
+            .labeled("protocol", protocol)
+            .labeled("mode", self.mode);
+        match self.probe {
+            Probe::Tcp { port } | Probe::Udp { port } => labels = labels.labeled("port", port),
+            Probe::Icmp(options) => {
+                if let Some(size) = options.size {
+                    labels = labels.labeled("size", size);
+                }
+                if options.df {
+                    labels = labels.labeled("df", true);
+                }
+                if let Some(ttl) = options.ttl {
+                    labels = labels.labeled("ttl", ttl);
+                }
+                if let Some(dscp) = self.dscp {
+                    labels = labels.labeled("dscp", dscp);
+                } else if let Some(tos) = options.tos {
+                    labels = labels.labeled("tos", tos);
+                }
+            }
+        }
+        let mut labels = labels
+            .labeled("count", self.count)
+            .labeled("ping_timeout", self.ping_timeout)
+            .labeled("resolve_timeout", self.resolve_timeout)
+            .labeled("addresses", self.addresses);
+        if let Some(ref resolver) = self.resolver {
+            labels = labels.labeled("resolver", resolver);
+        }
+        source_labels(labels, source)
+    }
+
+    fn ping_with(
+        &self,
+        pinger: &Pinger,
+        name: NameOrIpAddr,
+        protocol: Protocol,
+    ) -> impl Future<Item = Probed, Error = (StatusCode, Body)> {
+        let params = Params {
+            source: self.source.for_protocol(protocol),
+            ..self.params()
+        };
+        let future = pinger.ping(name.clone(), protocol, self.probe, &params);
+        let this = self.clone();
+        let pinger = pinger.clone();
+        future
+            .map_err(internal_error)
+            .and_then(move |report| this.probed(&pinger, name, protocol, report))
+    }
+
+    /// Labels the report and looks up names of the pinged addresses with `ptr=true`.
+    fn probed(
+        &self,
+        pinger: &Pinger,
+        name: NameOrIpAddr,
+        protocol: Protocol,
+        report: Report,
+    ) -> impl Future<Item = Probed, Error = (StatusCode, Body)> {
+        let labels = self.labels(&name, protocol, &self.source.for_protocol(protocol));
+
+        let addrs = match report {
+            Report::Success { ref results, .. } if self.ptr => {
+                results.iter().map(|&(addr, _)| addr).collect()
+            }
+            _ => Vec::new(),
+        };
+        let lookups: Vec<_> = addrs
+            .into_iter()
+            .map(|addr| {
+                pinger
+                    .reverse(addr, self.resolver.as_ref(), self.resolve_timeout)
+                    .map(move |name| name.map(|name| (addr, name)))
+            })
+            .collect();
+
+        future::join_all(lookups)
+            .map_err(internal_error)
+            .map(move |names| (labels, report, names.into_iter().flatten().collect()))
+    }
+}
+
+fn internal_error<E>(_err: E) -> (StatusCode, Body) {
+    let body = Body::from("Internal error");
+    (StatusCode::INTERNAL_SERVER_ERROR, body)
+}
+
+#[derive(Debug, Deserialize)]
+struct TracerouteRequest {
+    target: NameOrIpAddr,
     protocol: Option<Protocol>,
     count: Option<usize>,
+    max_hops: Option<u32>,
     ping_timeout: Option<u64>,
     resolve_timeout: Option<u64>,
+    buckets: Option<Buckets>,
+    source: Option<IpAddr>,
+    interface: Option<Interface>,
 }
 
+#[derive(Debug, Deserialize)]
+struct PmtuRequest {
+    target: NameOrIpAddr,
+    protocol: Option<Protocol>,
+    count: Option<usize>,
+    max_mtu: Option<usize>,
+    timeout: Option<u64>,
+    ping_timeout: Option<u64>,
+    resolve_timeout: Option<u64>,
+    source: Option<IpAddr>,
+    interface: Option<Interface>,
+}
+
+/// Largest MTU of IPv4 and IPv6 packets without jumbograms.
+const MAX_MTU: usize = 65535;
+
 struct App {
-    settings: Settings,
+    settings: SharedSettings,
     pinger: Pinger,
+    monitor: Monitor,
+    reloader: Reloader,
 }
 
 impl Service for App {
@@ -75,8 +420,21 @@ impl Service for App {
             if method == &Method::GET && (path == "/ping" || path == "/ping/") {
                 HTTP_PING.incr(1);
                 RequestType::Ping
+            } else if method == &Method::GET && (path == "/ping_many" || path == "/ping_many/") {
+                HTTP_PING_MANY.incr(1);
+                RequestType::PingMany
+            } else if method == &Method::GET && (path == "/traceroute" || path == "/traceroute/") {
+                HTTP_TRACEROUTE.incr(1);
+                RequestType::Traceroute
+            } else if method == &Method::GET && (path == "/pmtu" || path == "/pmtu/") {
+                HTTP_PMTU.incr(1);
+                RequestType::Pmtu
+            } else if method == &Method::GET && (path == "/targets" || path == "/targets/") {
+                RequestType::Targets
             } else if method == &Method::GET && (path == "/metrics" || path == "/metrics/") {
                 RequestType::Metrics
+            } else if method == &Method::POST && path == "/-/reload" {
+                RequestType::Reload
             } else {
                 RequestType::Unknown
             }
@@ -88,21 +446,68 @@ impl Service for App {
                 Body::from("Not Found"),
             ))),
             RequestType::Metrics => boxed(get_metrics()),
+            RequestType::Targets => {
+                boxed(future::ok(Body::from(self.monitor.metrics().to_string())))
+            }
+            RequestType::Reload => boxed(self.reloader.reload().then(|result| match result {
+                Ok(()) => Ok(Body::from("OK")),
+                Err(err) => Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Body::from(format!("Reload failed: {}", err)),
+                )),
+            })),
             RequestType::Ping => {
                 let query = req.uri().query().unwrap_or("");
+                let future = future::result(
+                    parse_query(query)
+                        .and_then(|request| parse_query(query).map(|options| (request, options))),
+                );
+
+                let settings = self.settings.get();
+                let pinger = self.pinger.clone();
+                let future = future
+                    .and_then(move |(request, options)| ping(request, options, settings, pinger));
+                boxed(future)
+            }
+            RequestType::PingMany => {
+                let query = req.uri().query().unwrap_or("");
+                let future = future::result(
+                    parse_query(query)
+                        .and_then(|request| parse_query(query).map(|options| (request, options))),
+                );
 
-                let mb_req = serde_urlencoded::from_str::<PingRequest>(query).map_err(|err| {
-                    (
-                        StatusCode::BAD_REQUEST,
-                        Body::from(format!("Bad Request: {}", err)),
-                    )
+                let settings = self.settings.get();
+                let pinger = self.pinger.clone();
+                let future = future.and_then(move |(request, options)| {
+                    ping_many(request, options, settings, pinger)
                 });
+                boxed(future)
+            }
+            RequestType::Traceroute => {
+                let query = req.uri().query().unwrap_or("");
+
+                let mb_req =
+                    serde_urlencoded::from_str::<TracerouteRequest>(query).map_err(|err| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            Body::from(format!("Bad Request: {}", err)),
+                        )
+                    });
 
                 let future = future::result(mb_req);
 
-                let settings = self.settings.clone();
+                let settings = self.settings.get();
                 let pinger = self.pinger.clone();
-                let future = future.and_then(move |request| ping(request, settings, pinger));
+                let future = future.and_then(move |request| traceroute(request, settings, pinger));
+                boxed(future)
+            }
+            RequestType::Pmtu => {
+                let query = req.uri().query().unwrap_or("");
+                let future = future::result(parse_query::<PmtuRequest>(query));
+
+                let settings = self.settings.get();
+                let pinger = self.pinger.clone();
+                let future = future.and_then(move |request| pmtu(request, settings, pinger));
                 boxed(future)
             }
         };
@@ -129,34 +534,117 @@ impl Service for App {
     }
 }
 
+fn parse_query<T: DeserializeOwned>(query: &str) -> Result<T, (StatusCode, Body)> {
+    serde_urlencoded::from_str::<T>(query).map_err(|err| {
+        (
+            StatusCode::BAD_REQUEST,
+            Body::from(format!("Bad Request: {}", err)),
+        )
+    })
+}
+
 fn get_metrics() -> impl Future<Item = Body, Error = ((StatusCode, Body))> {
-    future::result(format_metrics(&REPORTER.peek()))
+    future::result(format_metrics(&REPORTER.peek(), &resolver::metrics()))
 }
 
 fn ping(
     request: PingRequest,
+    options: PingOptions,
+    settings: Settings,
+    pinger: Pinger,
+) -> impl Future<Item = Body, Error = ((StatusCode, Body))> {
+    let params = match PingParams::new(options, &settings) {
+        Ok(params) => params,
+        Err(body) => return boxed(future::err((StatusCode::BAD_REQUEST, Body::from(body)))),
+    };
+
+    let future = params.ping(&pinger, request.target);
+
+    let future = future.and_then(move |reports| {
+        let mut exposition = Exposition::new();
+
+        for (labels, report, names) in reports {
+            set_metrics(&mut exposition, labels, &params.buckets, report, &names);
+        }
+
+        Ok(Body::from(exposition.to_string()))
+    });
+
+    boxed(future)
+}
+
+/// Pings all targets with the same parameters, at most `ping_many_concurrency` at once.
+///
+/// Repeated targets are pinged once, their series would collide otherwise.
+fn ping_many(
+    request: PingManyRequest,
+    options: PingOptions,
+    settings: Settings,
+    pinger: Pinger,
+) -> impl Future<Item = Body, Error = ((StatusCode, Body))> {
+    let params = match PingParams::new(options, &settings) {
+        Ok(params) => params,
+        Err(body) => return boxed(future::err((StatusCode::BAD_REQUEST, Body::from(body)))),
+    };
+
+    let mut targets: Vec<NameOrIpAddr> = Vec::with_capacity(request.targets.len());
+    for target in request.targets.iter() {
+        if !targets.contains(target) {
+            targets.push(target.clone());
+        }
+    }
+
+    let bad_request_body = if targets.is_empty() {
+        Some("No targets")
+    } else if targets.len() > settings.max_targets {
+        Some("Too many targets")
+    } else {
+        None
+    };
+    if let Some(body) = bad_request_body {
+        return boxed(future::err((StatusCode::BAD_REQUEST, Body::from(body))));
+    }
+
+    let buckets = params.buckets.clone();
+
+    // Futures are created lazily, resolving starts as soon as a future is created
+    let future = stream::iter_ok(targets)
+        .map(move |name| params.ping(&pinger, name))
+        .buffered(settings.ping_many_concurrency.max(1))
+        .collect();
+
+    let future = future.and_then(move |reports| {
+        let mut exposition = Exposition::new();
+
+        for (labels, report, names) in reports.into_iter().flatten() {
+            set_metrics(&mut exposition, labels, &buckets, report, &names);
+        }
+
+        Ok(Body::from(exposition.to_string()))
+    });
+
+    boxed(future)
+}
+
+fn traceroute(
+    request: TracerouteRequest,
     settings: Settings,
     pinger: Pinger,
 ) -> impl Future<Item = Body, Error = ((StatusCode, Body))> {
     let count = request.count.unwrap_or(settings.count);
+    let max_hops = request.max_hops.unwrap_or(settings.max_hops);
     let ping_timeout = request.ping_timeout.unwrap_or(settings.ping_timeout);
     let resolve_timeout = request.resolve_timeout.unwrap_or(settings.resolve_timeout);
+    let buckets = request.buckets.unwrap_or_else(|| settings.buckets.clone());
 
     let bad_request_body = {
-        if count > settings.max_count {
-            Some("Too many pings")
-        } else if count < 1 {
-            Some("Too few pings")
-        } else if ping_timeout > settings.max_ping_timeout {
-            Some("Too large ping timeout")
-        } else if ping_timeout < 5 {
-            Some("Too small ping timeout")
-        } else if resolve_timeout > settings.resolve_timeout {
-            Some("Too large resolve timeout")
-        } else if resolve_timeout < 5 {
-            Some("Too small resolve timeout")
+        if max_hops > settings.max_hops {
+            Some("Too many hops")
+        } else if max_hops < 1 {
+            Some("Too few hops")
         } else {
-            None
+            check_source(&settings, request.source, request.interface)
+                .or_else(|| check_limits(&settings, None, count, ping_timeout, resolve_timeout))
         }
     };
 
@@ -164,111 +652,579 @@ fn ping(
         return boxed(future::err((StatusCode::BAD_REQUEST, Body::from(body))));
     }
 
-    let mut protocol = request.protocol.unwrap_or(settings.protocol);
-    match &request.target {
-        &NameOrIpAddr::IpAddr(IpAddr::V4(_)) => protocol = Protocol::V4,
-        &NameOrIpAddr::IpAddr(IpAddr::V6(_)) => protocol = Protocol::V6,
-        _ => (),
+    let protocol = request
+        .target
+        .protocol(request.protocol.unwrap_or(settings.protocol));
+
+    if let Some(body) = check_source_protocol(request.source, protocol) {
+        return boxed(future::err((StatusCode::BAD_REQUEST, Body::from(body))));
     }
 
     let name = request.target;
 
-    let future = pinger.ping(name.clone(), protocol, count, resolve_timeout, ping_timeout);
+    let source = source(&settings, request.source, request.interface).for_protocol(protocol);
+
+    let params = Params {
+        resolver: None,
+        cache: true,
+        addresses: Addresses::Random,
+        source,
+        count,
+        resolve_timeout,
+        timeout: ping_timeout,
+    };
+    let future = pinger.traceroute(name.clone(), protocol, max_hops, &params);
     let future = future.map_err(|_| {
         let body = Body::from("Internal error");
         (StatusCode::INTERNAL_SERVER_ERROR, body)
     });
 
     let future = future.and_then(move |report| {
-        let (metrics, reporter) = tacho::new();
-        let metrics = metrics
+        let labels = Labels::new()
+            .labeled("target", name)
+            .labeled("protocol", protocol)
+            .labeled("count", count)
+            .labeled("max_hops", max_hops)
+            .labeled("ping_timeout", ping_timeout)
+            .labeled("resolve_timeout", resolve_timeout);
+        let labels = source_labels(labels, &source);
+        let mut exposition = Exposition::new();
+
+        set_traceroute_metrics(&mut exposition, labels, &buckets, report);
+
+        Ok(Body::from(exposition.to_string()))
+    });
+
+    boxed(future)
+}
+
+/// Probes are sent only until `timeout`, so a search with a large `max_mtu` fits into the scrape timeout.
+fn pmtu(
+    request: PmtuRequest,
+    settings: Settings,
+    pinger: Pinger,
+) -> impl Future<Item = Body, Error = ((StatusCode, Body))> {
+    let count = request.count.unwrap_or(settings.count);
+    let max_mtu = request.max_mtu.unwrap_or(1500);
+    let timeout = request.timeout.unwrap_or(settings.max_ping_timeout);
+    let ping_timeout = request.ping_timeout.unwrap_or(settings.ping_timeout);
+    let resolve_timeout = request.resolve_timeout.unwrap_or(settings.resolve_timeout);
+
+    let bad_request_body = {
+        if max_mtu > MAX_MTU {
+            Some("Too large MTU")
+        } else if max_mtu < 68 {
+            Some("Too small MTU")
+        } else if timeout > settings.max_ping_timeout {
+            Some("Too large timeout")
+        } else if timeout < ping_timeout {
+            Some("Timeout is less than ping timeout")
+        } else {
+            check_source(&settings, request.source, request.interface)
+                .or_else(|| check_limits(&settings, None, count, ping_timeout, resolve_timeout))
+        }
+    };
+
+    if let Some(body) = bad_request_body {
+        return boxed(future::err((StatusCode::BAD_REQUEST, Body::from(body))));
+    }
+
+    let protocol = request
+        .target
+        .protocol(request.protocol.unwrap_or(settings.protocol));
+
+    if let Some(body) = check_source_protocol(request.source, protocol) {
+        return boxed(future::err((StatusCode::BAD_REQUEST, Body::from(body))));
+    }
+
+    let name = request.target;
+
+    let source = source(&settings, request.source, request.interface).for_protocol(protocol);
+
+    let params = Params {
+        resolver: None,
+        cache: true,
+        addresses: Addresses::Random,
+        source,
+        count,
+        resolve_timeout,
+        timeout: ping_timeout,
+    };
+    let future = pinger
+        .pmtu(name.clone(), protocol, max_mtu, timeout, &params)
+        .map_err(internal_error);
+
+    let future = future.and_then(move |report| {
+        let labels = Labels::new()
             .labeled("target", name)
             .labeled("protocol", protocol)
             .labeled("count", count)
+            .labeled("max_mtu", max_mtu)
+            .labeled("timeout", timeout)
             .labeled("ping_timeout", ping_timeout)
             .labeled("resolve_timeout", resolve_timeout);
+        let labels = source_labels(labels, &source);
+        let mut exposition = Exposition::new();
 
-        set_metrics(metrics, report);
+        set_pmtu_metrics(&mut exposition, labels, report);
 
-        format_metrics(&reporter.peek())
+        Ok(Body::from(exposition.to_string()))
     });
 
     boxed(future)
 }
 
-pub fn set_metrics(mut metrics: tacho::Scope, report: Report) {
-    let resolve_error: Option<&'static str>;
+/// Only resolvers from the allowlist can be requested, module ones included.
+fn resolver_allowed(settings: &Settings, resolver: Option<&Upstream>) -> bool {
+    match resolver {
+        Some(resolver) => settings.allowed_resolvers.contains(resolver),
+        None => true,
+    }
+}
 
+/// Requested source addresses and interfaces must be allowed, the global ones are always used.
+fn check_source(
+    settings: &Settings,
+    addr: Option<IpAddr>,
+    interface: Option<Interface>,
+) -> Option<&'static str> {
+    match (addr, interface) {
+        (Some(ref addr), _) if !settings.allowed_sources.contains(addr) => {
+            Some("Source address is not allowed")
+        }
+        (_, Some(ref interface)) if !settings.allowed_interfaces.contains(interface) => {
+            Some("Interface is not allowed")
+        }
+        _ => None,
+    }
+}
+
+/// Checks the options merged with the module, missing values are checked as the global defaults.
+fn check_options(
+    options: &PingOptions,
+    settings: &Settings,
+    module: Option<&Module>,
+) -> Option<&'static str> {
+    let count = options.count.unwrap_or(settings.count);
+    let ping_timeout = options.ping_timeout.unwrap_or(settings.ping_timeout);
+    let resolve_timeout = options.resolve_timeout.unwrap_or(settings.resolve_timeout);
+    let mode = options.mode.unwrap_or(Mode::Icmp);
+
+    if let (Mode::Icmp, Some(_)) = (mode, options.port) {
+        Some("Port is not supported in icmp mode")
+    } else if let (Mode::Tcp, None) | (Mode::Udp, None) = (mode, options.port) {
+        Some("Port is required in tcp and udp modes")
+    } else if options.port == Some(0) {
+        Some("Invalid port")
+    } else if mode != Mode::Icmp
+        && (options.size.is_some()
+            || options.df.is_some()
+            || options.ttl.is_some()
+            || options.tos.is_some()
+            || options.dscp.is_some())
+    {
+        Some("Size, df, ttl, tos and dscp are supported only in icmp mode")
+    } else if options
+        .size
+        .map_or(false, |size| size > icmp::MAX_PAYLOAD_SIZE)
+    {
+        Some("Too large packet size")
+    } else if options.ttl.map_or(false, |ttl| ttl < 1 || ttl > 255) {
+        Some("Invalid ttl")
+    } else if options.tos.is_some() && options.dscp.is_some() {
+        Some("Tos and dscp can't be used together")
+    } else if options.dscp.map_or(false, |dscp| dscp > 63) {
+        Some("Invalid dscp")
+    } else if !resolver_allowed(settings, options.resolver.as_ref()) {
+        Some("Resolver is not allowed")
+    } else {
+        check_source(settings, options.source, options.interface)
+            .or_else(|| check_limits(settings, module, count, ping_timeout, resolve_timeout))
+    }
+}
+
+/// Modules are checked on load, so a broken one isn't accepted on reload instead of the old settings.
+pub fn check_module(module: &Module, settings: &Settings) -> Option<&'static str> {
+    check_options(
+        &PingOptions::default().with_module(module),
+        settings,
+        Some(module),
+    )
+}
+
+/// Unlike a global source address, a requested one isn't skipped over the other protocol.
+fn check_source_protocol(addr: Option<IpAddr>, protocol: Protocol) -> Option<&'static str> {
+    match addr {
+        Some(ref addr) if Protocol::of(addr) != protocol => {
+            Some("Source address doesn't match the protocol")
+        }
+        _ => None,
+    }
+}
+
+/// Requested source address and interface replace the global ones independently.
+fn source(settings: &Settings, addr: Option<IpAddr>, interface: Option<Interface>) -> Source {
+    Source {
+        addr: addr.or(settings.source.addr),
+        interface: interface.or(settings.source.interface),
+    }
+}
+
+/// Series sent from different addresses or interfaces are told apart by labels.
+fn source_labels(mut labels: Labels, source: &Source) -> Labels {
+    if let Some(addr) = source.addr {
+        labels = labels.labeled("source", addr);
+    }
+    if let Some(interface) = source.interface {
+        labels = labels.labeled("interface", interface);
+    }
+    labels
+}
+
+/// Module limits, when set, replace the global ones.
+fn check_limits(
+    settings: &Settings,
+    module: Option<&Module>,
+    count: usize,
+    ping_timeout: u64,
+    resolve_timeout: u64,
+) -> Option<&'static str> {
+    let max_count = module
+        .and_then(|module| module.max_count)
+        .unwrap_or(settings.max_count);
+    let max_ping_timeout = module
+        .and_then(|module| module.max_ping_timeout)
+        .unwrap_or(settings.max_ping_timeout);
+    let max_resolve_timeout = module
+        .and_then(|module| module.max_resolve_timeout)
+        .unwrap_or(settings.max_resolve_timeout);
+
+    if count > max_count {
+        Some("Too many pings")
+    } else if count < 1 {
+        Some("Too few pings")
+    } else if ping_timeout > max_ping_timeout {
+        Some("Too large ping timeout")
+    } else if ping_timeout < 5 {
+        Some("Too small ping timeout")
+    } else if resolve_timeout > max_resolve_timeout {
+        Some("Too large resolve timeout")
+    } else if resolve_timeout < 5 {
+        Some("Too small resolve timeout")
+    } else {
+        None
+    }
+}
+
+pub fn set_metrics(
+    exposition: &mut Exposition,
+    labels: Labels,
+    buckets: &Buckets,
+    report: Report,
+    names: &BTreeMap<IpAddr, Name>,
+) {
     match report {
-        Report::ResolveTimedOut => resolve_error = Some("timed out"),
-        Report::ResolveNotFound => resolve_error = Some("not found"),
-        Report::ResolveOtherError => resolve_error = Some("internal error"),
         Report::Success {
             resolve_time_ns,
-            addr,
-            pings,
+            answer,
+            results,
         } => {
-            resolve_error = None;
-            metrics = metrics.labeled("ip", addr);
-
-            metrics
-                .gauge(
-                    "ping_resolve_time",
-                    "Time it take to resolve domain to an IP address",
-                )
-                .set((resolve_time_ns / 1000000) as usize);
-
-            let times = metrics.stat("ping_times", "A histogram of round-trip times");
-
-            let mut failures = 0;
-            let mut successful = 0;
-            let total = pings.len();
-
-            for reply_time in pings {
-                match reply_time {
-                    Some(reply_time) => {
-                        times.add((reply_time * 1000.0) as u64);
-                        successful += 1;
-                    }
-                    None => {
-                        failures += 1;
-                    }
+            for (addr, pings) in results {
+                let labels = labels.clone().labeled("ip", addr);
+
+                set_resolve_time(exposition, &labels, resolve_time_ns);
+                set_answer_metrics(exposition, &labels, &answer);
+                if let Some(name) = names.get(&addr) {
+                    exposition.gauge(
+                        "ping_ptr_info",
+                        "Name of the pinged address from its PTR record in \"ptr\" label",
+                        &labels.clone().labeled("ptr", display_name(name)),
+                        1.0,
+                    );
                 }
+                set_ping_metrics(exposition, &labels, buckets, pings);
+                set_resolve_error(exposition, labels, None);
             }
+        }
+        report => set_resolve_error(exposition, labels, resolve_error(&report)),
+    }
+}
+
+fn set_ping_metrics(
+    exposition: &mut Exposition,
+    labels: &Labels,
+    buckets: &Buckets,
+    pings: Vec<Option<f64>>,
+) {
+    if let Some(stats) = rtt_stats(&pings) {
+        exposition.gauge(
+            "ping_rtt_min_seconds",
+            "Minimal round-trip time",
+            labels,
+            stats.min,
+        );
+        exposition.gauge(
+            "ping_rtt_max_seconds",
+            "Maximal round-trip time",
+            labels,
+            stats.max,
+        );
+        exposition.gauge(
+            "ping_rtt_avg_seconds",
+            "Average round-trip time",
+            labels,
+            stats.avg,
+        );
+        exposition.gauge(
+            "ping_rtt_stddev_seconds",
+            "Standard deviation of round-trip times",
+            labels,
+            stats.stddev,
+        );
+        exposition.gauge(
+            "ping_rtt_jitter_seconds",
+            "Interarrival jitter of round-trip times as defined in RFC 3550",
+            labels,
+            stats.jitter,
+        );
+    }
+
+    let total = pings.len();
+    let times: Vec<f64> = pings.into_iter().flatten().collect();
+    let successful = times.len();
+    let failures = total - successful;
+
+    exposition.histogram(
+        "ping_rtt_seconds",
+        "A histogram of round-trip times",
+        labels,
+        buckets,
+        &times,
+    );
+
+    exposition.gauge(
+        "ping_packets_total",
+        "Total number of sent pings",
+        labels,
+        total as f64,
+    );
+    exposition.gauge(
+        "ping_packets_success",
+        "Total number of success pings",
+        labels,
+        successful as f64,
+    );
+    exposition.gauge(
+        "ping_packets_failed",
+        "Total number of failed pings",
+        labels,
+        failures as f64,
+    );
 
-            metrics
-                .gauge("ping_packets_total", "Total number of sent pings")
-                .set(total);
-            metrics
-                .gauge("ping_packets_success", "Total number of success pings")
-                .set(successful);
-            metrics
-                .gauge("ping_packets_failed", "Total number of failed pings")
-                .set(failures);
-
-            if total > 0 {
-                let loss = failures as f64 / total as f64 * 100.0;
-                metrics
-                    .gauge(
-                        "ping_packets_loss",
-                        "A percentage of failed pings from the total pings",
-                    )
-                    .set(loss as usize);
+    if total > 0 {
+        exposition.gauge(
+            "ping_packets_loss_ratio",
+            "A ratio of failed pings to the total pings",
+            labels,
+            failures as f64 / total as f64,
+        );
+    }
+}
+
+pub fn set_traceroute_metrics(
+    exposition: &mut Exposition,
+    labels: Labels,
+    buckets: &Buckets,
+    report: Report<Vec<Hop>>,
+) {
+    match report {
+        Report::Success {
+            resolve_time_ns,
+            answer,
+            results,
+        } => {
+            for (addr, hops) in results {
+                let labels = labels.clone().labeled("ip", addr);
+
+                set_resolve_time(exposition, &labels, resolve_time_ns);
+                set_answer_metrics(exposition, &labels, &answer);
+                set_hop_metrics(exposition, &labels, buckets, hops);
+                set_resolve_error(exposition, labels, None);
             }
         }
-    };
+        report => set_resolve_error(exposition, labels, resolve_error(&report)),
+    }
+}
+
+pub fn set_pmtu_metrics(exposition: &mut Exposition, labels: Labels, report: Report<PathMtu>) {
+    match report {
+        Report::Success {
+            resolve_time_ns,
+            answer,
+            results,
+        } => {
+            for (addr, path_mtu) in results {
+                let labels = labels.clone().labeled("ip", addr);
+
+                set_resolve_time(exposition, &labels, resolve_time_ns);
+                set_answer_metrics(exposition, &labels, &answer);
+                if let Some(mtu) = path_mtu.mtu {
+                    exposition.gauge(
+                        "ping_path_mtu_bytes",
+                        "Largest packet that reaches the target without fragmentation",
+                        &labels,
+                        mtu as f64,
+                    );
+                }
+                if let Some(mtu) = path_mtu.reported_mtu {
+                    exposition.gauge(
+                        "ping_path_mtu_reported_bytes",
+                        "Smallest MTU reported by ICMP fragmentation needed messages",
+                        &labels,
+                        f64::from(mtu),
+                    );
+                }
+                set_resolve_error(exposition, labels, None);
+            }
+        }
+        report => set_resolve_error(exposition, labels, resolve_error(&report)),
+    }
+}
+
+fn set_hop_metrics(
+    exposition: &mut Exposition,
+    labels: &Labels,
+    buckets: &Buckets,
+    hops: Vec<Hop>,
+) {
+    for (index, hop) in hops.into_iter().enumerate() {
+        let hop_labels = labels.clone().labeled("hop", index + 1);
+
+        let mut responders: BTreeMap<IpAddr, Vec<f64>> = BTreeMap::new();
+        let total = hop.len();
+        let mut failures = 0;
+
+        for reply in hop {
+            match reply {
+                Some(reply) => responders.entry(reply.from).or_default().push(reply.time),
+                None => failures += 1,
+            }
+        }
+
+        // Loss is reported once per hop, with the IP that has answered the most
+        let primary = responders
+            .iter()
+            .max_by_key(|&(_, times)| times.len())
+            .map(|(ip, _)| ip.to_string())
+            .unwrap_or_default();
+
+        for (ip, times) in responders {
+            exposition.histogram(
+                "ping_hop_rtt_seconds",
+                "A histogram of round-trip times to the hop",
+                &hop_labels.clone().labeled("hop_ip", ip),
+                buckets,
+                &times,
+            );
+        }
+
+        if total > 0 {
+            exposition.gauge(
+                "ping_hop_loss_ratio",
+                "A ratio of failed probes to the total probes to the hop",
+                &hop_labels.labeled("hop_ip", primary),
+                failures as f64 / total as f64,
+            );
+        }
+    }
+}
+
+fn resolve_error<T>(report: &Report<T>) -> Option<&'static str> {
+    match report {
+        &Report::ResolveTimedOut => Some("timed out"),
+        &Report::ResolveNotFound => Some("not found"),
+        &Report::ResolveNoData => Some("no address for protocol"),
+        &Report::ResolveServFail => Some("server failure"),
+        &Report::ResolveRefused => Some("query refused"),
+        &Report::ResolveConnectionFailed => Some("connection failed"),
+        &Report::ResolveInvalidResponse => Some("invalid response"),
+        &Report::ResolveOtherError => Some("internal error"),
+        &Report::Success { .. } => None,
+    }
+}
+
+fn set_resolve_time(exposition: &mut Exposition, labels: &Labels, resolve_time_ns: u64) {
+    exposition.gauge(
+        "ping_resolve_time_seconds",
+        "Time it take to resolve domain to an IP address",
+        labels,
+        resolve_time_ns as f64 / 1_000_000_000.0,
+    );
+}
+
+fn set_answer_metrics(exposition: &mut Exposition, labels: &Labels, answer: &Answer) {
+    exposition.gauge(
+        "ping_resolve_answers",
+        "Number of addresses the name is resolved to",
+        labels,
+        answer.addrs.len() as f64,
+    );
+    if let Some(ttl) = answer.ttl {
+        exposition.gauge(
+            "ping_resolve_ttl_seconds",
+            "Minimal TTL of the address records and CNAME records leading to them",
+            labels,
+            ttl as f64,
+        );
+    }
+    // A separate gauge, as a label would split the series every time the answer expires
+    if let Some(cache) = answer.cache {
+        exposition.gauge(
+            "ping_resolve_cache_hit",
+            "Boolean metric if the answer has been taken from the cache",
+            labels,
+            if cache == CacheStatus::Hit { 1.0 } else { 0.0 },
+        );
+    }
+    if !answer.cnames.is_empty() {
+        let chain: Vec<String> = answer.cnames.iter().map(display_name).collect();
+        exposition.gauge(
+            "ping_resolve_cname_info",
+            "CNAME chain from the target to the canonical name in \"cname_chain\" label",
+            &labels.clone().labeled("cname_chain", chain.join(",")),
+            1.0,
+        );
+    }
+}
 
+/// Name without the trailing dot, IDNA labels are kept in ASCII.
+fn display_name(name: &Name) -> String {
+    name.to_ascii().trim_end_matches('.').to_string()
+}
+
+fn set_resolve_error(
+    exposition: &mut Exposition,
+    mut labels: Labels,
+    resolve_error: Option<&'static str>,
+) {
     if let Some(error) = resolve_error {
-        metrics = metrics.labeled("error", error);
+        labels = labels.labeled("error", error);
     }
 
-    metrics.gauge("ping_resolve_error", "Boolean metric if there's an error during the resolve (error message will be in \"error\" label)")
-        .set(resolve_error.map(|_| 1).unwrap_or(0));
+    exposition.gauge(
+        "ping_resolve_error",
+        "Boolean metric if there's an error during the resolve (error message will be in \"error\" label)",
+        &labels,
+        resolve_error.map(|_| 1.0).unwrap_or(0.0),
+    );
 }
 
-fn format_metrics(report: &tacho::Report) -> Result<Body, (StatusCode, Body)> {
+fn format_metrics(
+    report: &tacho::Report,
+    exposition: &Exposition,
+) -> Result<Body, (StatusCode, Body)> {
     match tacho::prometheus::string(report) {
-        Ok(s) => Ok(Body::from(s)),
+        Ok(s) => Ok(Body::from(s + &exposition.to_string())),
         Err(_) => Err((
             StatusCode::INTERNAL_SERVER_ERROR,
             Body::from("Internal Error"),
@@ -276,11 +1232,22 @@ fn format_metrics(report: &tacho::Report) -> Result<Body, (StatusCode, Body)> {
     }
 }
 
-pub fn server(settings: Settings, pinger: Pinger) -> impl Future<Item = (), Error = ()> {
-    let builder = Server::try_bind(&settings.listen);
+pub fn server(
+    settings: SharedSettings,
+    pinger: Pinger,
+    monitor: Monitor,
+    reloader: Reloader,
+) -> impl Future<Item = (), Error = ()> {
+    let listen = settings.get().listen;
+    let builder = Server::try_bind(&listen);
     let future = future::result(builder).and_then(move |builder| {
-        info!("Listening on {}", &settings.listen);
-        builder.serve(NewApp { settings, pinger })
+        info!("Listening on {}", listen);
+        builder.serve(NewApp {
+            settings,
+            pinger,
+            monitor,
+            reloader,
+        })
     });
     let future = future.map_err(|error| {
         error!("Server error: {}", error);
@@ -290,10 +1257,146 @@ pub fn server(settings: Settings, pinger: Pinger) -> impl Future<Item = (), Erro
 
 #[cfg(test)]
 mod tests {
-    use super::init;
+    use std::str::FromStr;
+
+    use serde_urlencoded;
+
+    use super::{check_limits, check_module, init, PingOptions, PingParams};
+    use pinger::Probe;
+    use resolver::Upstream;
+    use settings::{Module, Settings};
+    use utils::{Mode, NameOrIpAddr, Protocol};
+
+    fn options(query: &str) -> PingOptions {
+        serde_urlencoded::from_str(query).expect("options")
+    }
+
+    fn params(settings: &Settings, query: &str) -> Result<PingParams, &'static str> {
+        PingParams::new(options(query), settings)
+    }
+
+    fn tos(params: PingParams) -> Option<u8> {
+        match params.probe {
+            Probe::Icmp(options) => options.tos,
+            _ => None,
+        }
+    }
+
+    fn labels(params: PingParams) -> String {
+        let name = NameOrIpAddr::IpAddr([127, 0, 0, 1].into());
+        params
+            .labels(&name, Protocol::V4, &params.source)
+            .to_string()
+    }
 
     #[test]
     fn test_lazy_static() {
         init()
     }
+
+    #[test]
+    fn test_limits() {
+        let settings = Settings::load(None).expect("settings");
+        let resolve_timeout = settings.max_resolve_timeout;
+        assert_eq!(
+            check_limits(&settings, None, 1, 1000, resolve_timeout),
+            None
+        );
+        assert_eq!(
+            check_limits(&settings, None, 1, 1000, resolve_timeout + 1),
+            Some("Too large resolve timeout")
+        );
+
+        let module = Module {
+            max_resolve_timeout: Some(100),
+            ..Module::default()
+        };
+        assert_eq!(
+            check_limits(&settings, Some(&module), 1, 1000, 101),
+            Some("Too large resolve timeout")
+        );
+    }
+
+    #[test]
+    fn test_check_module() {
+        let settings = Settings::load(None).expect("settings");
+        let check = |module| check_module(&module, &settings);
+
+        assert_eq!(
+            check(Module {
+                dscp: Some(46),
+                ..Module::default()
+            }),
+            None
+        );
+        assert_eq!(
+            check(Module {
+                dscp: Some(99),
+                ..Module::default()
+            }),
+            Some("Invalid dscp")
+        );
+        assert_eq!(
+            check(Module {
+                mode: Some(Mode::Tcp),
+                ..Module::default()
+            }),
+            Some("Port is required in tcp and udp modes")
+        );
+        assert_eq!(
+            check(Module {
+                resolver: Some(Upstream::from_str("192.0.2.53").expect("upstream")),
+                ..Module::default()
+            }),
+            Some("Resolver is not allowed")
+        );
+        assert_eq!(
+            check(Module {
+                resolve_timeout: Some(5000),
+                max_resolve_timeout: Some(1000),
+                ..Module::default()
+            }),
+            Some("Too large resolve timeout")
+        );
+    }
+
+    #[test]
+    fn test_marking() {
+        let settings = Settings::load(None).expect("settings");
+
+        assert_eq!(params(&settings, "ttl=0").err(), Some("Invalid ttl"));
+        assert_eq!(params(&settings, "ttl=256").err(), Some("Invalid ttl"));
+        assert_eq!(params(&settings, "dscp=64").err(), Some("Invalid dscp"));
+        assert_eq!(
+            params(&settings, "tos=184&dscp=46").err(),
+            Some("Tos and dscp can't be used together")
+        );
+        assert_eq!(
+            params(&settings, "mode=tcp&port=443&ttl=5").err(),
+            Some("Size, df, ttl, tos and dscp are supported only in icmp mode")
+        );
+
+        assert_eq!(params(&settings, "dscp=46").map(tos), Ok(Some(184)));
+        assert_eq!(params(&settings, "tos=16").map(tos), Ok(Some(16)));
+        assert!(params(&settings, "dscp=46")
+            .map(labels)
+            .expect("labels")
+            .contains(",dscp=\"46\","));
+        assert!(params(&settings, "tos=16")
+            .map(labels)
+            .expect("labels")
+            .contains(",tos=\"16\","));
+
+        let module = Module {
+            tos: Some(184),
+            ..Module::default()
+        };
+        let merged = |query| {
+            let options = options(query).with_module(&module);
+            (options.tos, options.dscp)
+        };
+        assert_eq!(merged(""), (Some(184), None));
+        assert_eq!(merged("dscp=10"), (None, Some(10)));
+        assert_eq!(merged("tos=16"), (Some(16), None));
+    }
 }
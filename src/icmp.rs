@@ -0,0 +1,425 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use futures::{future, Async, Future, Poll};
+use libc::{self, c_int};
+use mio::unix::EventedFd;
+use mio::{self, Evented, PollOpt, Ready, Token};
+use rand::random;
+use socket2::{Domain, Protocol, SockAddr, Socket as RawSocket, Type};
+use tokio::reactor::PollEvented2;
+use tokio::timer::Timeout;
+
+use socket::{set_option, Source};
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+const ICMP_HEADER_SIZE: usize = 8;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const PAYLOAD_SIZE: usize = 56;
+/// Largest payload of an echo request that fits into an IPv4 packet.
+pub const MAX_PAYLOAD_SIZE: usize = 65507;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    EchoReply,
+    TimeExceeded,
+    Unreachable,
+    /// The packet is larger than the MTU of the next hop and can't be fragmented.
+    FragmentationNeeded {
+        mtu: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Reply {
+    pub from: IpAddr,
+    pub kind: Kind,
+    pub time: f64,
+}
+
+/// Size of the IP and ICMP headers of an echo request to the address, without IPv4 options.
+pub fn header_size(addr: IpAddr) -> usize {
+    match addr {
+        IpAddr::V4(_) => IPV4_HEADER_SIZE + ICMP_HEADER_SIZE,
+        IpAddr::V6(_) => IPV6_HEADER_SIZE + ICMP_HEADER_SIZE,
+    }
+}
+
+/// Echo request parameters, system defaults are used for missing ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Options {
+    pub ttl: Option<u32>,
+    /// Payload size in bytes, without the ICMP and IP headers.
+    pub size: Option<usize>,
+    /// Sets the don't fragment bit, otherwise the packet may be fragmented.
+    pub df: bool,
+    /// Type of service byte, or traffic class for IPv6, with DSCP in the upper six bits.
+    pub tos: Option<u8>,
+    pub source: Source,
+}
+
+/// Sends a single echo request and waits for a reply to it,
+/// which could come either from the target or from an intermediate hop.
+pub fn probe(
+    addr: IpAddr,
+    options: Options,
+    timeout: Duration,
+) -> impl Future<Item = Option<Reply>, Error = io::Error> {
+    let future = future::result(Probe::new(addr, options)).and_then(|probe| probe);
+    Timeout::new(future, timeout).then(|result| match result {
+        Ok(reply) => Ok(Some(reply)),
+        Err(err) => match err.into_inner() {
+            Some(ref err) if is_lost(err) => Ok(None),
+            Some(err) => Err(err),
+            None => Ok(None),
+        },
+    })
+}
+
+/// Errors of the send path mean the probe is lost, like in tcp and udp modes:
+/// packets larger than the known path MTU can't be sent with the DF bit,
+/// and there may be no route to the target or no source address.
+fn is_lost(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(libc::EMSGSIZE)
+        | Some(libc::ENETUNREACH)
+        | Some(libc::EHOSTUNREACH)
+        | Some(libc::ENETDOWN)
+        | Some(libc::EHOSTDOWN)
+        | Some(libc::EADDRNOTAVAIL) => true,
+        _ => false,
+    }
+}
+
+struct Socket {
+    inner: RawSocket,
+}
+
+impl Evented for Socket {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.inner.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.inner.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.inner.as_raw_fd()).deregister(poll)
+    }
+}
+
+struct Probe {
+    socket: PollEvented2<Socket>,
+    addr: IpAddr,
+    ident: u16,
+    seq: u16,
+    packet: Vec<u8>,
+    buffer: Vec<u8>,
+    sent_at: Option<u64>,
+}
+
+impl Probe {
+    fn new(addr: IpAddr, options: Options) -> io::Result<Self> {
+        let inner = match addr {
+            IpAddr::V4(_) => {
+                let socket = RawSocket::new(Domain::ipv4(), Type::raw(), Some(Protocol::icmpv4()))?;
+                if let Some(ttl) = options.ttl {
+                    socket.set_ttl(ttl)?;
+                }
+                let pmtudisc = if options.df {
+                    libc::IP_PMTUDISC_DO
+                } else {
+                    libc::IP_PMTUDISC_DONT
+                };
+                set_option(&socket, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, pmtudisc)?;
+                if let Some(tos) = options.tos {
+                    set_option(&socket, libc::IPPROTO_IP, libc::IP_TOS, c_int::from(tos))?;
+                }
+                socket
+            }
+            IpAddr::V6(_) => {
+                let socket = RawSocket::new(Domain::ipv6(), Type::raw(), Some(Protocol::icmpv6()))?;
+                if let Some(ttl) = options.ttl {
+                    socket.set_unicast_hops_v6(ttl)?;
+                }
+                let pmtudisc = if options.df {
+                    libc::IPV6_PMTUDISC_DO
+                } else {
+                    libc::IPV6_PMTUDISC_DONT
+                };
+                set_option(
+                    &socket,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_MTU_DISCOVER,
+                    pmtudisc,
+                )?;
+                if let Some(tos) = options.tos {
+                    set_option(
+                        &socket,
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_TCLASS,
+                        c_int::from(tos),
+                    )?;
+                }
+                socket
+            }
+        };
+        options.source.bind(&inner, addr)?;
+        inner.set_nonblocking(true)?;
+
+        let ident = random();
+        let seq = random();
+        Ok(Probe {
+            socket: PollEvented2::new(Socket { inner }),
+            addr,
+            ident,
+            seq,
+            packet: echo_request(addr, ident, seq, options.size.unwrap_or(PAYLOAD_SIZE)),
+            buffer: vec![0; 2048],
+            sent_at: None,
+        })
+    }
+}
+
+impl Future for Probe {
+    type Item = Reply;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.sent_at.is_none() {
+            try_ready!(self.socket.poll_write_ready());
+            let dest = SockAddr::from(SocketAddr::new(self.addr, 0));
+            match self.socket.get_ref().inner.send_to(&self.packet, &dest) {
+                Ok(_) => self.sent_at = Some(::time::precise_time_ns()),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.socket.clear_write_ready()?;
+                    return Ok(Async::NotReady);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        loop {
+            try_ready!(self.socket.poll_read_ready(Ready::readable()));
+            let (size, from) = match self.socket.get_ref().inner.recv_from(&mut self.buffer) {
+                Ok(result) => result,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.socket.clear_read_ready(Ready::readable())?;
+                    return Ok(Async::NotReady);
+                }
+                Err(err) => return Err(err),
+            };
+
+            let packet = &self.buffer[..size];
+            let kind = match self.addr {
+                IpAddr::V4(_) => parse_v4(packet, self.ident, self.seq),
+                IpAddr::V6(_) => parse_v6(packet, self.ident, self.seq),
+            };
+            let from = from
+                .as_inet()
+                .map(|addr| IpAddr::V4(*addr.ip()))
+                .or_else(|| from.as_inet6().map(|addr| IpAddr::V6(*addr.ip())));
+
+            if let (Some(kind), Some(from), Some(sent_at)) = (kind, from, self.sent_at) {
+                let time = (::time::precise_time_ns() - sent_at) as f64 / 1_000_000_000.0;
+                return Ok(Async::Ready(Reply { from, kind, time }));
+            }
+        }
+    }
+}
+
+fn echo_request(addr: IpAddr, ident: u16, seq: u16, size: usize) -> Vec<u8> {
+    let mut packet = vec![0; ICMP_HEADER_SIZE + size];
+    packet[4..6].copy_from_slice(&ident.to_be_bytes());
+    packet[6..8].copy_from_slice(&seq.to_be_bytes());
+    match addr {
+        IpAddr::V4(_) => {
+            packet[0] = ICMP_ECHO_REQUEST;
+            let checksum = checksum(&packet);
+            packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+        // The kernel calculates ICMPv6 checksums for raw sockets
+        IpAddr::V6(_) => packet[0] = ICMPV6_ECHO_REQUEST,
+    }
+    packet
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data.chunks(2).fold(0u32, |sum, chunk| {
+        let word = match chunk {
+            &[high, low] => u16::from_be_bytes([high, low]),
+            &[high] => u16::from_be_bytes([high, 0]),
+            _ => 0,
+        };
+        sum + u32::from(word)
+    });
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Raw IPv4 sockets receive packets with the IP header.
+fn skip_ipv4_header(packet: &[u8]) -> Option<&[u8]> {
+    let header_size = (packet.first()? & 0x0f) as usize * 4;
+    packet.get(header_size..)
+}
+
+fn is_our_echo(echo: &[u8], ident: u16, seq: u16) -> bool {
+    echo.len() >= ICMP_HEADER_SIZE
+        && u16::from_be_bytes([echo[4], echo[5]]) == ident
+        && u16::from_be_bytes([echo[6], echo[7]]) == seq
+}
+
+fn parse_v4(packet: &[u8], ident: u16, seq: u16) -> Option<Kind> {
+    let icmp = skip_ipv4_header(packet)?;
+    let (kind, echo) = match (*icmp.first()?, *icmp.get(1)?) {
+        (ICMP_ECHO_REPLY, _) => (Kind::EchoReply, icmp),
+        (ICMP_TIME_EXCEEDED, _) => (
+            Kind::TimeExceeded,
+            skip_ipv4_header(icmp.get(ICMP_HEADER_SIZE..)?)?,
+        ),
+        // The next-hop MTU is in the second half of the unused field (RFC 1191)
+        (ICMP_DEST_UNREACHABLE, ICMP_FRAGMENTATION_NEEDED) => (
+            Kind::FragmentationNeeded {
+                mtu: u32::from(u16::from_be_bytes([*icmp.get(6)?, *icmp.get(7)?])),
+            },
+            skip_ipv4_header(icmp.get(ICMP_HEADER_SIZE..)?)?,
+        ),
+        (ICMP_DEST_UNREACHABLE, _) => (
+            Kind::Unreachable,
+            skip_ipv4_header(icmp.get(ICMP_HEADER_SIZE..)?)?,
+        ),
+        _ => return None,
+    };
+
+    if is_our_echo(echo, ident, seq) {
+        Some(kind)
+    } else {
+        None
+    }
+}
+
+fn parse_v6(icmp: &[u8], ident: u16, seq: u16) -> Option<Kind> {
+    let (kind, echo) = match *icmp.first()? {
+        ICMPV6_ECHO_REPLY => (Kind::EchoReply, icmp),
+        ICMPV6_TIME_EXCEEDED => (
+            Kind::TimeExceeded,
+            icmp.get(ICMP_HEADER_SIZE + IPV6_HEADER_SIZE..)?,
+        ),
+        ICMPV6_PACKET_TOO_BIG => (
+            Kind::FragmentationNeeded {
+                mtu: u32::from_be_bytes([
+                    *icmp.get(4)?,
+                    *icmp.get(5)?,
+                    *icmp.get(6)?,
+                    *icmp.get(7)?,
+                ]),
+            },
+            icmp.get(ICMP_HEADER_SIZE + IPV6_HEADER_SIZE..)?,
+        ),
+        ICMPV6_DEST_UNREACHABLE => (
+            Kind::Unreachable,
+            icmp.get(ICMP_HEADER_SIZE + IPV6_HEADER_SIZE..)?,
+        ),
+        _ => return None,
+    };
+
+    if is_our_echo(echo, ident, seq) {
+        Some(kind)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use libc;
+
+    use super::{checksum, echo_request, is_lost, parse_v4, parse_v6, Kind};
+
+    #[test]
+    fn test_echo_request_checksum() {
+        let packet = echo_request(IpAddr::V4(Ipv4Addr::LOCALHOST), 0x1234, 1, 56);
+        assert_eq!(checksum(&packet), 0);
+        let packet = echo_request(IpAddr::V4(Ipv4Addr::LOCALHOST), 0x1234, 1, 1473);
+        assert_eq!(packet.len(), 1481);
+        assert_eq!(checksum(&packet), 0);
+    }
+
+    #[test]
+    fn test_is_lost() {
+        assert!(is_lost(&io::Error::from_raw_os_error(libc::ENETUNREACH)));
+        assert!(is_lost(&io::Error::from_raw_os_error(libc::EADDRNOTAVAIL)));
+        assert!(!is_lost(&io::Error::from_raw_os_error(libc::EPERM)));
+        assert!(!is_lost(&io::Error::new(io::ErrorKind::Other, "other")));
+    }
+
+    #[test]
+    fn test_parse_time_exceeded() {
+        let echo = echo_request(IpAddr::V4(Ipv4Addr::LOCALHOST), 0x1234, 1, 56);
+        let mut packet = vec![0x45; 1];
+        packet.extend_from_slice(&[0; 19]);
+        packet.extend_from_slice(&[11, 0, 0, 0, 0, 0, 0, 0]);
+        packet.push(0x45);
+        packet.extend_from_slice(&[0; 19]);
+        packet.extend_from_slice(&echo[..8]);
+
+        assert_eq!(parse_v4(&packet, 0x1234, 1), Some(Kind::TimeExceeded));
+        assert_eq!(parse_v4(&packet, 0x1234, 2), None);
+    }
+
+    #[test]
+    fn test_parse_fragmentation_needed() {
+        let echo = echo_request(IpAddr::V4(Ipv4Addr::LOCALHOST), 0x1234, 1, 56);
+        let mut packet = vec![0x45; 1];
+        packet.extend_from_slice(&[0; 19]);
+        packet.extend_from_slice(&[3, 4, 0, 0, 0, 0, 0x05, 0xb4]);
+        packet.push(0x45);
+        packet.extend_from_slice(&[0; 19]);
+        packet.extend_from_slice(&echo[..8]);
+        assert_eq!(
+            parse_v4(&packet, 0x1234, 1),
+            Some(Kind::FragmentationNeeded { mtu: 1460 })
+        );
+
+        let echo = echo_request(IpAddr::V6(Ipv6Addr::LOCALHOST), 0x1234, 1, 56);
+        let mut packet = vec![2, 0, 0, 0, 0, 0, 0x05, 0x00];
+        packet.extend_from_slice(&[0; 40]);
+        packet.extend_from_slice(&echo[..8]);
+        assert_eq!(
+            parse_v6(&packet, 0x1234, 1),
+            Some(Kind::FragmentationNeeded { mtu: 1280 })
+        );
+    }
+}
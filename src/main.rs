@@ -2,10 +2,13 @@
 
 #[macro_use]
 extern crate failure;
+#[macro_use]
 extern crate futures;
 extern crate hyper;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
+extern crate mio;
 extern crate rand;
 extern crate serde;
 #[macro_use]
@@ -19,24 +22,36 @@ extern crate slog_async;
 extern crate slog_scope;
 extern crate slog_stdlog;
 extern crate slog_term;
+extern crate socket2;
 extern crate tacho;
 extern crate time;
 extern crate tokio;
 extern crate tokio_ping;
 extern crate tokio_signal;
+extern crate toml;
 extern crate trust_dns_resolver;
 
+use std::env;
+use std::path::PathBuf;
+
 use futures::future::Either;
 use futures::sync::oneshot;
 use futures::{Future, Stream};
 use slog::Drain;
-use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
 
+mod exposition;
+mod hosts;
 mod http;
+mod icmp;
 mod metrics;
+mod monitor;
 mod pinger;
+mod reload;
 mod resolver;
 mod settings;
+mod socket;
+mod stats;
 mod utils;
 
 fn signals() -> impl Future<Item = i32, Error = ::std::io::Error> {
@@ -54,6 +69,33 @@ fn signals() -> impl Future<Item = i32, Error = ::std::io::Error> {
     })
 }
 
+/// Parses command line arguments, the only supported one is `--config <path>` (or `-c <path>`).
+fn config_path() -> Result<Option<PathBuf>, String> {
+    let mut args = env::args().skip(1);
+    let mut config = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(path) => config = Some(PathBuf::from(path)),
+                None => return Err(format!("missing value for {}", arg)),
+            },
+            other => return Err(format!("unknown argument: {}", other)),
+        }
+    }
+    Ok(config)
+}
+
+/// Reloads settings on every SIGHUP, errors are logged by the reloader.
+fn reload_on_sighup(reloader: reload::Reloader) -> impl Future<Item = (), Error = ()> {
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .map_err(|err| error!("Signal handling error: {}", err))
+        .for_each(move |_| {
+            info!("Got SIGHUP, reloading settings");
+            reloader.reload().then(|_| Ok(()))
+        })
+}
+
 fn run() -> i32 {
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
@@ -70,7 +112,15 @@ fn run() -> i32 {
         env!("CARGO_PKG_VERSION")
     ));
 
-    let settings = match settings::Settings::from_env() {
+    let config = match config_path() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err);
+            return 1;
+        }
+    };
+
+    let settings = match settings::Settings::load(config) {
         Ok(settings) => settings,
         Err(err) => {
             error!("{}", err);
@@ -91,7 +141,15 @@ fn run() -> i32 {
             .map_err(|_| {
                 error!("Unable to create pinger, please check capabilities");
             })
-            .and_then(move |pinger| http::server(settings, pinger));
+            .and_then(move |pinger| {
+                let settings = settings::SharedSettings::new(settings);
+                let monitor = monitor::Monitor::new(&settings.get());
+                let reloader =
+                    reload::Reloader::new(settings.clone(), pinger.clone(), monitor.clone());
+                tokio::spawn(monitor.run(settings.clone(), pinger.clone()));
+                tokio::spawn(reload_on_sighup(reloader.clone()));
+                http::server(settings, pinger, monitor, reloader)
+            });
 
         let signals_future = signals().map_err(|_| {
             error!("Signal handling error");
@@ -0,0 +1,220 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Loop};
+use futures::Future;
+use tokio::timer::Delay;
+
+use exposition::{Buckets, Exposition, Histogram, Labels};
+use icmp;
+use pinger::{Params, Pinger, Probe, Report};
+use settings::{Settings, SharedSettings};
+use stats::rtt_stats;
+use utils::{Addresses, NameOrIpAddr, Protocol};
+
+/// Pings the configured targets in background and accumulates results between scrapes.
+#[derive(Clone)]
+pub struct Monitor {
+    targets: Arc<RwLock<Vec<Arc<Target>>>>,
+}
+
+struct Target {
+    name: NameOrIpAddr,
+    protocol: Protocol,
+    window_size: usize,
+    buckets: Buckets,
+    state: Mutex<State>,
+}
+
+struct State {
+    sent: u64,
+    received: u64,
+    resolve_errors: u64,
+    rtt: Histogram,
+    window: VecDeque<Option<f64>>,
+}
+
+impl Monitor {
+    pub fn new(settings: &Settings) -> Self {
+        let monitor = Monitor {
+            targets: Arc::new(RwLock::new(Vec::new())),
+        };
+        monitor.update(settings);
+        monitor
+    }
+
+    /// Replaces the list of targets, targets with unchanged settings keep their state.
+    pub fn update(&self, settings: &Settings) {
+        let mut targets = self.targets.write().expect("monitor targets");
+
+        let updated = settings
+            .targets
+            .iter()
+            .map(|name| {
+                let target = Target::new(name.clone(), settings);
+                match targets.iter().find(|old| old.same_as(&target)) {
+                    Some(old) => old.clone(),
+                    None => Arc::new(target),
+                }
+            })
+            .collect();
+
+        *targets = updated;
+    }
+
+    /// Sends a ping to every target each interval, never resolves.
+    ///
+    /// Every ping is spawned as a separate task, so a slow reply doesn't shift the schedule.
+    /// Settings are read on every tick, so reloaded targets and timeouts apply without a restart.
+    pub fn run(
+        &self,
+        settings: SharedSettings,
+        pinger: Pinger,
+    ) -> impl Future<Item = (), Error = ()> {
+        let targets = self.targets.clone();
+
+        future::loop_fn(Instant::now(), move |deadline| {
+            let targets = targets.clone();
+            let settings = settings.get();
+            let pinger = pinger.clone();
+
+            Delay::new(deadline)
+                .map_err(|err| error!("Timer error: {}", err))
+                .map(move |()| {
+                    for target in targets.read().expect("monitor targets").iter() {
+                        let target = target.clone();
+                        let future = pinger
+                            .ping(
+                                target.name.clone(),
+                                target.protocol,
+                                Probe::Icmp(icmp::Options::default()),
+                                &Params {
+                                    resolver: None,
+                                    cache: true,
+                                    addresses: Addresses::Random,
+                                    source: settings.source,
+                                    count: 1,
+                                    resolve_timeout: settings.resolve_timeout,
+                                    timeout: settings.ping_timeout,
+                                },
+                            )
+                            .then(move |result| {
+                                match result {
+                                    Ok(report) => target.record(report),
+                                    Err(err) => warn!("Unable to ping {}: {}", target.name, err),
+                                }
+                                Ok(())
+                            });
+                        ::tokio::spawn(future);
+                    }
+
+                    Loop::Continue::<(), _>(deadline + Duration::from_millis(settings.interval))
+                })
+        })
+    }
+
+    pub fn metrics(&self) -> Exposition {
+        let mut exposition = Exposition::new();
+
+        for target in self.targets.read().expect("monitor targets").iter() {
+            let labels = Labels::new()
+                .labeled("target", &target.name)
+                .labeled("protocol", target.protocol);
+            let state = target.state.lock().expect("monitor state");
+
+            exposition.counter(
+                "ping_target_packets_sent_total",
+                "Total number of pings sent to the target",
+                &labels,
+                state.sent as f64,
+            );
+            exposition.counter(
+                "ping_target_packets_received_total",
+                "Total number of replies received from the target",
+                &labels,
+                state.received as f64,
+            );
+            exposition.counter(
+                "ping_target_resolve_errors_total",
+                "Total number of errors during the resolve of the target",
+                &labels,
+                state.resolve_errors as f64,
+            );
+            exposition.histogram_from(
+                "ping_target_rtt_seconds",
+                "A histogram of round-trip times to the target",
+                &labels,
+                &state.rtt,
+            );
+
+            if !state.window.is_empty() {
+                let window: Vec<Option<f64>> = state.window.iter().cloned().collect();
+                let failures = window.iter().filter(|time| time.is_none()).count();
+                exposition.gauge(
+                    "ping_target_window_loss_ratio",
+                    "A ratio of failed pings to the total pings in the rolling window",
+                    &labels,
+                    failures as f64 / window.len() as f64,
+                );
+                if let Some(stats) = rtt_stats(&window) {
+                    exposition.gauge(
+                        "ping_target_window_rtt_avg_seconds",
+                        "Average round-trip time in the rolling window",
+                        &labels,
+                        stats.avg,
+                    );
+                }
+            }
+        }
+
+        exposition
+    }
+}
+
+impl Target {
+    fn new(name: NameOrIpAddr, settings: &Settings) -> Self {
+        Target {
+            protocol: name.protocol(settings.protocol),
+            name,
+            window_size: settings.window.max(1),
+            buckets: settings.buckets.clone(),
+            state: Mutex::new(State {
+                sent: 0,
+                received: 0,
+                resolve_errors: 0,
+                rtt: Histogram::new(&settings.buckets),
+                window: VecDeque::with_capacity(settings.window),
+            }),
+        }
+    }
+
+    fn same_as(&self, other: &Target) -> bool {
+        self.name == other.name
+            && self.protocol == other.protocol
+            && self.window_size == other.window_size
+            && self.buckets == other.buckets
+    }
+
+    fn record(&self, report: Report) {
+        let mut state = self.state.lock().expect("monitor state");
+
+        match report {
+            Report::Success { results, .. } => {
+                for ping in results.into_iter().flat_map(|(_, pings)| pings) {
+                    state.sent += 1;
+                    if let Some(time) = ping {
+                        state.received += 1;
+                        state.rtt.observe(time);
+                    }
+
+                    if state.window.len() == self.window_size {
+                        state.window.pop_front();
+                    }
+                    state.window.push_back(ping);
+                }
+            }
+            _ => state.resolve_errors += 1,
+        }
+    }
+}
@@ -1,15 +1,27 @@
-use std::net::IpAddr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpStream as StdTcpStream, UdpSocket as StdUdpSocket};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use futures::future::{ok, Either};
-use futures::{future, Future, Stream};
-use tokio::timer::Timeout;
+use futures::future::{ok, Either, Loop};
+use futures::sync::oneshot;
+use futures::{future, stream, Async, Future, Stream};
+use mio::unix::UnixReady;
+use mio::Ready;
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::reactor::Handle;
+use tokio::timer::{Delay, Timeout};
 use tokio_ping::{Error as PingError, Pinger as LowLevelPinger};
+use trust_dns_resolver::Name;
 
-use resolver::{Error as ResolveError, Resolver};
+use hosts::Hosts;
+use icmp;
+use resolver::{Answer, Error as ResolveError, Resolver, Upstream};
 use settings::Settings;
-use utils::{NameOrIpAddr, Protocol};
+use socket::Source;
+use utils::{boxed, boxed_stream, Addresses, NameOrIpAddr, Protocol};
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -17,6 +29,8 @@ pub enum Error {
     PingError { error: PingError },
     #[fail(display = "create resolver error")]
     CreateResolverError { error: ResolveError },
+    #[fail(display = "icmp error")]
+    IcmpError { error: io::Error },
 }
 
 impl From<PingError> for Error {
@@ -25,117 +39,723 @@ impl From<PingError> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::IcmpError { error }
+    }
+}
+
 impl From<ResolveError> for Error {
     fn from(error: ResolveError) -> Self {
         Error::CreateResolverError { error }
     }
 }
 
-pub enum Report {
+pub enum Report<T = Vec<Option<f64>>> {
+    /// NXDOMAIN, or no addresses of any protocol.
     ResolveNotFound,
+    /// The name has addresses of the other protocol only.
+    ResolveNoData,
+    ResolveServFail,
+    ResolveRefused,
+    ResolveConnectionFailed,
+    ResolveInvalidResponse,
     ResolveTimedOut,
     ResolveOtherError,
+    /// Results for every pinged address, sorted by address.
     Success {
         resolve_time_ns: u64,
-        addr: IpAddr,
-        pings: Vec<Option<f64>>,
+        answer: Answer,
+        results: Vec<(IpAddr, T)>,
     },
 }
 
+/// Delay before trying IPv4 if IPv6 hasn't replied yet, recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Ping over one of the protocols, raced against the other one.
+struct Racer {
+    protocol: Protocol,
+    /// Resolved on the first reply, canceled if there is none.
+    replied: oneshot::Receiver<()>,
+    report: Box<Future<Item = Report, Error = Error> + Send>,
+}
+
+/// State of a `Racer` when it has either got a reply or finished.
+enum Attempt {
+    /// The rest of the probes are still being sent.
+    Replied(Protocol, Box<Future<Item = Report, Error = Error> + Send>),
+    Failed(Protocol, Report),
+}
+
+/// Replies to the probes sent with the same TTL.
+pub type Hop = Vec<Option<icmp::Reply>>;
+
+/// Minimal MTU every link must support, RFC 791 and RFC 8200.
+const MIN_MTU_V4: usize = 68;
+const MIN_MTU_V6: usize = 1280;
+
+/// Result of the path MTU discovery, sizes are of IP packets in bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PathMtu {
+    /// Largest packet the target has replied to, missing if there are no replies at all.
+    pub mtu: Option<usize>,
+    /// Smallest MTU reported by ICMP "fragmentation needed" or "packet too big" messages.
+    pub reported_mtu: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Probe {
+    /// Echo requests with default options are sent with `tokio_ping`.
+    Icmp(icmp::Options),
+    Tcp {
+        port: u16,
+    },
+    Udp {
+        port: u16,
+    },
+}
+
+/// How the target is resolved and probed, shared by all kinds of probes.
+#[derive(Debug, Clone)]
+pub struct Params {
+    /// Upstream to resolve the name with instead of the configured ones.
+    pub resolver: Option<Upstream>,
+    /// The cached result is ignored if not set.
+    pub cache: bool,
+    pub addresses: Addresses,
+    /// Probes of any kind are sent from it.
+    pub source: Source,
+    pub count: usize,
+    pub resolve_timeout: u64,
+    /// Timeout of each probe.
+    pub timeout: u64,
+}
+
 #[derive(Clone)]
 pub struct Pinger {
     inner: Arc<PingerInner>,
 }
 
 struct PingerInner {
-    resolver: Resolver,
+    resolver: RwLock<Arc<Resolver>>,
+    /// Resolvers for upstreams requested explicitly, created on first use.
+    overrides: Mutex<HashMap<Upstream, Arc<Resolver>>>,
     pinger: LowLevelPinger,
 }
 
 impl Pinger {
     pub fn new(settings: Settings) -> impl Future<Item = Self, Error = Error> {
-        let resolver_future = Resolver::new(settings).map_err(From::from);
+        let resolver_future = Resolver::new(
+            settings.resolvers.to_vec(),
+            settings.resolver_strategy,
+            settings.hosts.clone(),
+            settings.cache,
+        )
+        .map_err(From::from);
         let pinger_future = LowLevelPinger::new().map_err(From::from);
         resolver_future
             .join(pinger_future)
             .and_then(|(resolver, pinger)| {
                 Ok(Self {
-                    inner: Arc::new(PingerInner { resolver, pinger }),
+                    inner: Arc::new(PingerInner {
+                        resolver: RwLock::new(Arc::new(resolver)),
+                        overrides: Mutex::new(HashMap::new()),
+                        pinger,
+                    }),
                 })
             })
     }
 
+    /// Replaces the resolver, lookups in progress finish with the old one.
+    pub fn set_resolver(&self, resolver: Resolver) {
+        *self.inner.resolver.write().expect("resolver") = Arc::new(resolver);
+    }
+
+    /// Replaces static hosts of the resolver, keeping its upstreams.
+    pub fn set_hosts(&self, hosts: Hosts) {
+        let mut resolver = self.inner.resolver.write().expect("resolver");
+        *resolver = Arc::new(resolver.with_hosts(hosts));
+    }
+
+    /// Returns the resolver for the upstream, or the configured one if there is none.
+    fn resolver(&self, upstream: Option<&Upstream>) -> Arc<Resolver> {
+        match upstream {
+            Some(upstream) => self
+                .inner
+                .overrides
+                .lock()
+                .expect("resolver overrides")
+                .entry(upstream.clone())
+                .or_insert_with(|| Arc::new(Resolver::single(upstream)))
+                .clone(),
+            None => self.inner.resolver.read().expect("resolver").clone(),
+        }
+    }
+
+    /// Sends `count` probes to every selected address of the name.
     pub fn ping(
         &self,
         name: NameOrIpAddr,
         protocol: Protocol,
-        count: usize,
-        resolve_timeout: u64,
-        timeout: u64,
+        probe: Probe,
+        params: &Params,
     ) -> impl Future<Item = Report, Error = Error> {
-        let resolve_timeout = Duration::from_millis(resolve_timeout);
-        let timeout = Duration::from_millis(timeout);
+        self.ping_notify(name, protocol, probe, params, None)
+    }
 
-        let future = self.inner.resolver.resolve(name, protocol);
-        let future = Timeout::new(future, resolve_timeout);
+    /// Same as `ping`, `replied` is notified of the first reply from any of the addresses.
+    fn ping_notify(
+        &self,
+        name: NameOrIpAddr,
+        protocol: Protocol,
+        probe: Probe,
+        params: &Params,
+        replied: Option<oneshot::Sender<()>>,
+    ) -> impl Future<Item = Report, Error = Error> {
+        let timeout = Duration::from_millis(params.timeout);
+        let source = params.source;
+        let count = params.count;
+        let probe = match probe {
+            Probe::Icmp(options) => Probe::Icmp(icmp::Options { source, ..options }),
+            probe => probe,
+        };
 
         let pinger = self.inner.pinger.clone();
-        let future = future.then(move |result| match result {
-            Ok((resolve_time_ns, addr)) => {
-                let stream = pinger
-                    .chain(addr)
-                    .timeout(timeout)
-                    .stream()
-                    .map_err(From::from)
-                    .take(count as u64);
-
-                let future = stream.fold(Vec::new(), |mut acc, result| {
+        let replied = Arc::new(Mutex::new(replied));
+        self.resolve(name, protocol, params, move |addr| {
+            let stream = match probe {
+                Probe::Icmp(options) if options == icmp::Options::default() => boxed_stream(
+                    pinger
+                        .chain(addr)
+                        .timeout(timeout)
+                        .stream()
+                        .map_err(From::from),
+                ),
+                Probe::Icmp(options) => boxed_stream(stream::repeat(()).and_then(move |()| {
+                    icmp::probe(addr, options, timeout)
+                        .map(|reply| match reply {
+                            Some(ref reply) if reply.kind == icmp::Kind::EchoReply => {
+                                Some(reply.time)
+                            }
+                            _ => None,
+                        })
+                        .map_err(Error::from)
+                })),
+                Probe::Tcp { port } => {
+                    let addr = SocketAddr::new(addr, port);
+                    boxed_stream(
+                        stream::repeat(()).and_then(move |()| tcp_connect(addr, source, timeout)),
+                    )
+                }
+                Probe::Udp { port } => {
+                    let addr = SocketAddr::new(addr, port);
+                    boxed_stream(
+                        stream::repeat(()).and_then(move |()| udp_probe(addr, source, timeout)),
+                    )
+                }
+            };
+
+            let replied = replied.clone();
+            stream
+                .take(count as u64)
+                .inspect(move |result| {
+                    if result.is_some() {
+                        if let Some(sender) = replied.lock().expect("replied lock").take() {
+                            let _ = sender.send(());
+                        }
+                    }
+                })
+                .fold(Vec::new(), |mut acc, result| {
                     acc.push(result);
                     future::ok::<Vec<Option<f64>>, Error>(acc)
-                });
+                })
+        })
+    }
+
+    /// Finds the name of the address, `None` if there is no PTR record or the lookup has failed.
+    pub fn reverse(
+        &self,
+        addr: IpAddr,
+        resolver: Option<&Upstream>,
+        resolve_timeout: u64,
+    ) -> impl Future<Item = Option<Name>, Error = Error> {
+        let resolve_timeout = Duration::from_millis(resolve_timeout);
+        let future = self.resolver(resolver).reverse(addr, resolve_timeout);
+        Timeout::new(future, resolve_timeout).then(|result| Ok(result.ok()))
+    }
+
+    /// Pings a name the way a Happy Eyeballs (RFC 8305) client would connect to it.
+    ///
+    /// IPv6 is pinged first and IPv4 after the connection attempt delay or as soon as IPv6 fails,
+    /// the first protocol that replies wins and its probes are completed, the other one is
+    /// dropped. If there are no replies at all IPv6 is preferred, unless only the IPv4 address
+    /// has been resolved.
+    pub fn happy_eyeballs(
+        &self,
+        name: NameOrIpAddr,
+        probe: Probe,
+        params: &Params,
+    ) -> impl Future<Item = (Protocol, Report), Error = Error> {
+        let pinger = self.clone();
+        let params = params.clone();
+        let racer = move |protocol| {
+            let (sender, replied) = oneshot::channel();
+            let report = pinger.ping_notify(name.clone(), protocol, probe, &params, Some(sender));
+            Racer {
+                protocol,
+                replied,
+                report: boxed(report),
+            }
+        };
+
+        let v6 = racer(Protocol::V6);
+        race(v6, move || racer(Protocol::V4))
+    }
+
+    /// Sends `count` TTL-limited probes for every hop up to `max_hops`,
+    /// hops after the first one where the target has replied are dropped.
+    ///
+    /// Only one of the resolved addresses is traced.
+    pub fn traceroute(
+        &self,
+        name: NameOrIpAddr,
+        protocol: Protocol,
+        max_hops: u32,
+        params: &Params,
+    ) -> impl Future<Item = Report<Vec<Hop>>, Error = Error> {
+        let timeout = Duration::from_millis(params.timeout);
+        let source = params.source;
+        let count = params.count;
 
-                Either::A(future.and_then(move |pings| {
-                    Ok(Report::Success {
-                        resolve_time_ns,
-                        addr,
-                        pings,
+        self.resolve(name, protocol, params, move |addr| {
+            let hops = (1..=max_hops).map(move |ttl| {
+                stream::iter_ok(0..count)
+                    .and_then(move |_| {
+                        let options = icmp::Options {
+                            ttl: Some(ttl),
+                            source,
+                            ..icmp::Options::default()
+                        };
+                        icmp::probe(addr, options, timeout)
                     })
-                }))
+                    .collect()
+                    .map_err(Error::from)
+            });
+
+            future::join_all(hops).map(|mut hops: Vec<Hop>| {
+                let reached = hops.iter().position(|hop| {
+                    hop.iter().any(|reply| match reply {
+                        &Some(ref reply) => reply.kind == icmp::Kind::EchoReply,
+                        &None => false,
+                    })
+                });
+                if let Some(index) = reached {
+                    hops.truncate(index + 1);
+                }
+                hops
+            })
+        })
+    }
+
+    /// Finds the largest packet up to `max_mtu` that reaches the target without fragmentation
+    /// with a binary search, `count` probes of each size are sent at once.
+    /// The search of each address takes at most `search_timeout`.
+    pub fn pmtu(
+        &self,
+        name: NameOrIpAddr,
+        protocol: Protocol,
+        max_mtu: usize,
+        search_timeout: u64,
+        params: &Params,
+    ) -> impl Future<Item = Report<PathMtu>, Error = Error> {
+        let timeout = Duration::from_millis(params.timeout);
+        let search_timeout = Duration::from_millis(search_timeout);
+        let source = params.source;
+        let count = params.count;
+
+        self.resolve(name, protocol, params, move |addr| {
+            path_mtu(addr, source, count, max_mtu, timeout, search_timeout)
+        })
+    }
+
+    /// Resolves the name and calls `f` for every selected address, all of them are pinged in parallel.
+    fn resolve<F, R>(
+        &self,
+        name: NameOrIpAddr,
+        protocol: Protocol,
+        params: &Params,
+        f: F,
+    ) -> impl Future<Item = Report<R::Item>, Error = Error>
+    where
+        F: Fn(IpAddr) -> R,
+        R: Future<Error = Error>,
+    {
+        let resolve_timeout = Duration::from_millis(params.resolve_timeout);
+        let addresses = params.addresses;
+        let cache = params.cache;
+
+        let resolver = self.resolver(params.resolver.as_ref());
+        let future = resolver.resolve(name.clone(), protocol, cache, resolve_timeout);
+        let future = Timeout::new(future, resolve_timeout);
+
+        future.then(move |result| match result {
+            Ok((resolve_time_ns, answer)) => {
+                // The CNAME chain is followed while pinging with a timeout of its own,
+                // it's left empty if it can't be followed
+                let cnames = resolver.cname_chain(name, protocol, &answer, resolve_timeout);
+                let cnames = Timeout::new(cnames, resolve_timeout)
+                    .then(|result| Ok(result.unwrap_or_default()));
+
+                let futures: Vec<_> = addresses
+                    .select(answer.addrs.clone())
+                    .into_iter()
+                    .map(|addr| f(addr).map(move |item| (addr, item)))
+                    .collect();
+                Either::A(
+                    future::join_all(futures)
+                        .join(cnames)
+                        .map(move |(results, cnames)| Report::Success {
+                            resolve_time_ns,
+                            answer: Answer { cnames, ..answer },
+                            results,
+                        }),
+                )
             }
             Err(err) => Either::B(ok(match err.into_inner() {
                 Some(ResolveError::NotFound) => Report::ResolveNotFound,
+                Some(ResolveError::NoData) => Report::ResolveNoData,
+                Some(ResolveError::ServFail) => Report::ResolveServFail,
+                Some(ResolveError::Refused) => Report::ResolveRefused,
+                Some(ResolveError::ConnectionFailed) => Report::ResolveConnectionFailed,
+                Some(ResolveError::InvalidResponse) => Report::ResolveInvalidResponse,
                 Some(ResolveError::Error) => Report::ResolveOtherError,
-                None => Report::ResolveTimedOut,
+                Some(ResolveError::TimedOut) | None => Report::ResolveTimedOut,
             })),
-        });
-
-        future.and_then(|report| Ok(report))
-        //
-        //        let future = future.map_err(|err| match err.into_inner() {
-        //            Some(err) => err.into(),
-        //            None => Error::ResolveTimeoutError {}
-        //        });
-        //
-        //        let pinger = self.inner.pinger.clone();
-        //        let future = future.and_then(move |(resolve_time_ns, addr)| {
-        //            let stream = pinger.chain(addr)
-        //                .timeout(timeout)
-        //                .stream()
-        //                .map_err(From::from)
-        //                .take(count as u64);
-        //
-        //            let future = stream.fold(Vec::new(), |mut acc, result| {
-        //                acc.push(result);
-        //                future::ok::<Vec<Option<f64>>, Error>(acc)
-        //            });
-        //
-        //            future.and_then(move |times| {
-        //                Ok(Report::Success {
-        //                    resolve_time_ns,
-        //                    addr,
-        //                    times,
-        //                })
-        //            })
-        //        });
+        })
+    }
+}
+
+/// The largest size is tried first, as the path MTU usually isn't reduced, and so is a reported MTU,
+/// as it's likely to be the right one. Only replies of the target count.
+///
+/// A round is started only if it ends before `search_timeout`, the best result so far is returned otherwise.
+fn path_mtu(
+    addr: IpAddr,
+    source: Source,
+    count: usize,
+    max_mtu: usize,
+    timeout: Duration,
+    search_timeout: Duration,
+) -> impl Future<Item = PathMtu, Error = Error> {
+    let deadline = Instant::now() + search_timeout;
+    let min_mtu = match addr {
+        IpAddr::V4(_) => MIN_MTU_V4,
+        IpAddr::V6(_) => MIN_MTU_V6,
+    };
+    let initial = (PathMtu::default(), min_mtu.min(max_mtu), max_mtu, true);
+
+    future::loop_fn(initial, move |(result, low, high, probe_high)| {
+        let mtu = if probe_high {
+            high
+        } else {
+            low + (high - low) / 2
+        };
+        let options = icmp::Options {
+            size: Some(mtu - icmp::header_size(addr)),
+            df: true,
+            source,
+            ..icmp::Options::default()
+        };
+        let probes = (0..count).map(move |_| icmp::probe(addr, options, timeout));
+
+        future::join_all(probes)
+            .map_err(Error::from)
+            .map(move |replies| {
+                let mut result = result;
+                let mut reported = None;
+                let mut replied = false;
+                for reply in replies.into_iter().flatten() {
+                    match reply.kind {
+                        icmp::Kind::EchoReply => replied = true,
+                        icmp::Kind::FragmentationNeeded { mtu } if mtu > 0 => {
+                            reported = Some(reported.map_or(mtu, |other: u32| other.min(mtu)));
+                        }
+                        _ => (),
+                    }
+                }
+                if let Some(reported) = reported {
+                    result.reported_mtu = Some(
+                        result
+                            .reported_mtu
+                            .map_or(reported, |other| other.min(reported)),
+                    );
+                }
+
+                let (low, high, probe_high) = if replied {
+                    result.mtu = Some(mtu);
+                    (mtu + 1, high, false)
+                } else {
+                    match reported {
+                        Some(reported) if (reported as usize) < mtu => {
+                            (low, reported as usize, true)
+                        }
+                        _ => (low, mtu - 1, false),
+                    }
+                };
+                if low > high || Instant::now() + timeout > deadline {
+                    Loop::Break(result)
+                } else {
+                    Loop::Continue((result, low, high, probe_high))
+                }
+            })
+    })
+}
+
+/// Races IPv6 against IPv4, started after the connection attempt delay or as soon as IPv6 fails.
+fn race<F>(v6: Racer, v4: F) -> impl Future<Item = (Protocol, Report), Error = Error>
+where
+    F: FnOnce() -> Racer + Send + 'static,
+{
+    let (failed, v6_failed) = oneshot::channel();
+    let v6 = first_reply(v6).map(move |attempt| {
+        if let Attempt::Failed(..) = attempt {
+            let _ = failed.send(());
+        }
+        attempt
+    });
+    let v4 = Delay::new(Instant::now() + CONNECTION_ATTEMPT_DELAY)
+        .select2(v6_failed)
+        .then(move |_| first_reply(v4()));
+
+    v6.select2(v4).then(|result| match result {
+        Ok(Either::A((first, rest))) => pick_attempt(first, rest),
+        Ok(Either::B((first, rest))) => pick_attempt(first, rest),
+        Err(Either::A((err, _))) => boxed(future::err(err)),
+        Err(Either::B((err, _))) => boxed(future::err(err)),
+    })
+}
+
+/// Waits for the first reply of the racer or for its report if there is no reply.
+fn first_reply(racer: Racer) -> impl Future<Item = Attempt, Error = Error> {
+    let protocol = racer.protocol;
+    racer
+        .replied
+        .select2(racer.report)
+        .then(move |result| match result {
+            Ok(Either::A(((), report))) => boxed(future::ok(Attempt::Replied(protocol, report))),
+            Ok(Either::B((report, _))) => boxed(future::ok(Attempt::Failed(protocol, report))),
+            Err(Either::A((_, report))) => {
+                boxed(report.map(move |report| Attempt::Failed(protocol, report)))
+            }
+            Err(Either::B((err, _))) => boxed(future::err(err)),
+        })
+}
+
+fn pick_attempt<F>(
+    first: Attempt,
+    rest: F,
+) -> Box<Future<Item = (Protocol, Report), Error = Error> + Send>
+where
+    F: Future<Item = Attempt, Error = Error> + Send + 'static,
+{
+    let (first, first_report) = match first {
+        Attempt::Replied(protocol, report) => {
+            return boxed(report.map(move |report| (protocol, report)))
+        }
+        Attempt::Failed(protocol, report) => (protocol, report),
+    };
+
+    boxed(rest.and_then(move |second| {
+        let second = match second {
+            Attempt::Replied(protocol, report) => {
+                return boxed(report.map(move |report| (protocol, report)))
+            }
+            Attempt::Failed(protocol, report) => (protocol, report),
+        };
+        let (v6, v4) = match first {
+            Protocol::V6 => ((first, first_report), second),
+            Protocol::V4 => (second, (first, first_report)),
+        };
+        let resolved = |report: &Report| match *report {
+            Report::Success { .. } => true,
+            _ => false,
+        };
+        boxed(future::ok(if resolved(&v4.1) && !resolved(&v6.1) {
+            v4
+        } else {
+            v6
+        }))
+    }))
+}
+
+/// Measures the time of a TCP handshake, `None` means the connection
+/// was refused or not established in time.
+fn tcp_connect(
+    addr: SocketAddr,
+    source: Source,
+    timeout: Duration,
+) -> impl Future<Item = Option<f64>, Error = Error> {
+    let st = ::time::precise_time_ns();
+    let future = future::result(tcp_socket(addr, source))
+        .and_then(move |stream| TcpStream::connect_std(stream, &addr, &Handle::default()))
+        .map(move |_stream| (::time::precise_time_ns() - st) as f64 / 1_000_000_000.0);
+    Timeout::new(future, timeout).then(|result| Ok(result.ok()))
+}
+
+/// Creates a TCP socket bound to the source, it's connected by `tokio`.
+fn tcp_socket(addr: SocketAddr, source: Source) -> io::Result<StdTcpStream> {
+    let socket = Socket::new(domain(addr), Type::stream(), None)?;
+    source.bind(&socket, addr.ip())?;
+    Ok(socket.into_tcp_stream())
+}
+
+static UDP_PAYLOAD: &[u8] = b"ping-exporter";
+
+/// Sends a datagram and waits for either an echo (RFC 862) or an ICMP port
+/// unreachable, both of them mean that the packet has reached the host.
+fn udp_probe(
+    addr: SocketAddr,
+    source: Source,
+    timeout: Duration,
+) -> impl Future<Item = Option<f64>, Error = Error> {
+    let st = ::time::precise_time_ns();
+    let future = future::result(udp_socket(addr, source)).and_then(|(mut socket, std_socket)| {
+        let mut sent = false;
+        let mut buf = [0; 512];
+        future::poll_fn(move || {
+            if !sent {
+                try_ready!(socket.poll_send(UDP_PAYLOAD));
+                sent = true;
+            }
+
+            // ICMP errors are reported through the socket error, not as readable data
+            try_ready!(socket.poll_read_ready(Ready::readable() | UnixReady::error()));
+            if let Some(err) = std_socket.take_error()? {
+                return match err.kind() {
+                    io::ErrorKind::ConnectionRefused => Ok(Async::Ready(())),
+                    _ => Err(err),
+                };
+            }
+
+            match socket.poll_recv(&mut buf) {
+                Ok(Async::Ready(_)) => Ok(Async::Ready(())),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    Ok(Async::Ready(()))
+                }
+                Err(err) => Err(err),
+            }
+        })
+    });
+    let future = future.map(move |()| (::time::precise_time_ns() - st) as f64 / 1_000_000_000.0);
+    Timeout::new(future, timeout).then(|result| Ok(result.ok()))
+}
+
+/// Creates a connected UDP socket bound to the source, the std clone is used to read
+/// the pending socket error.
+fn udp_socket(addr: SocketAddr, source: Source) -> io::Result<(UdpSocket, StdUdpSocket)> {
+    let socket = Socket::new(domain(addr), Type::dgram(), None)?;
+    source.bind(&socket, addr.ip())?;
+    let std_socket = socket.into_udp_socket();
+    std_socket.connect(addr)?;
+    let socket = UdpSocket::from_std(std_socket.try_clone()?, &Handle::default())?;
+    Ok((socket, std_socket))
+}
+
+fn domain(addr: SocketAddr) -> Domain {
+    match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use futures::sync::oneshot;
+    use futures::{future, Future};
+    use tokio::runtime::Runtime;
+    use tokio::timer::Delay;
+
+    use super::{race, Racer, Report};
+    use resolver::Answer;
+    use utils::{boxed, Protocol};
+
+    /// Replies after `replied` ms if it's set and finishes after `finished` ms.
+    fn racer(protocol: Protocol, resolved: bool, replied: Option<u64>, finished: u64) -> Racer {
+        let addr = match protocol {
+            Protocol::V4 => IpAddr::from([192, 0, 2, 1]),
+            Protocol::V6 => IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]),
+        };
+        let report = if resolved {
+            Report::Success {
+                resolve_time_ns: 0,
+                answer: Answer {
+                    addrs: vec![addr],
+                    ttl: None,
+                    canonical: None,
+                    cnames: Vec::new(),
+                    cache: None,
+                },
+                results: vec![(addr, vec![replied.map(|time| time as f64 / 1000.0)])],
+            }
+        } else {
+            Report::ResolveNoData
+        };
+
+        let start = Instant::now();
+        let (sender, receiver) = oneshot::channel();
+        let reply = match replied {
+            Some(time) => boxed(
+                Delay::new(start + Duration::from_millis(time)).then(move |_| sender.send(())),
+            ),
+            None => boxed(future::ok::<(), ()>(())),
+        };
+        let report = reply
+            .then(move |_| Delay::new(start + Duration::from_millis(finished)))
+            .then(move |_| Ok(report));
+        Racer {
+            protocol,
+            replied: receiver,
+            report: boxed(report),
+        }
+    }
+
+    fn run(v6: (bool, Option<u64>, u64), v4: (bool, Option<u64>, u64)) -> (Protocol, Duration) {
+        let mut runtime = Runtime::new().expect("runtime");
+        let start = Instant::now();
+        let (protocol, _) = runtime
+            .block_on(future::lazy(move || {
+                race(racer(Protocol::V6, v6.0, v6.1, v6.2), move || {
+                    racer(Protocol::V4, v4.0, v4.1, v4.2)
+                })
+            }))
+            .expect("race");
+        (protocol, start.elapsed())
+    }
+
+    #[test]
+    fn test_race() {
+        // IPv6 replies first but finishes last.
+        let (protocol, elapsed) = run((true, Some(100), 500), (true, Some(10), 50));
+        assert_eq!(protocol, Protocol::V6);
+        assert!(elapsed >= Duration::from_millis(500));
+
+        // IPv6 replies only after IPv4 has started and replied.
+        let (protocol, elapsed) = run((true, Some(400), 500), (true, Some(10), 50));
+        assert_eq!(protocol, Protocol::V4);
+        assert!(elapsed < Duration::from_millis(400));
+
+        // IPv4 is started as soon as IPv6 fails.
+        let (protocol, elapsed) = run((true, None, 10), (true, Some(10), 50));
+        assert_eq!(protocol, Protocol::V4);
+        assert!(elapsed < Duration::from_millis(250));
+
+        assert_eq!(run((false, None, 0), (true, None, 0)).0, Protocol::V4);
+        assert_eq!(run((true, None, 0), (true, None, 0)).0, Protocol::V6);
+        assert_eq!(run((true, None, 0), (false, None, 0)).0, Protocol::V6);
     }
 }
@@ -0,0 +1,91 @@
+use futures::future::{self, Either};
+use futures::Future;
+
+use monitor::Monitor;
+use pinger::Pinger;
+use resolver::{Error as ResolveError, Resolver};
+use settings::{Error as SettingsError, Settings, SharedSettings};
+use utils::boxed;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "{}", error)]
+    SettingsError { error: SettingsError },
+    #[fail(display = "unable to create resolver")]
+    CreateResolverError { error: ResolveError },
+}
+
+/// Applies new settings to the running exporter, triggered by SIGHUP or `POST /-/reload`.
+#[derive(Clone)]
+pub struct Reloader {
+    settings: SharedSettings,
+    pinger: Pinger,
+    monitor: Monitor,
+}
+
+impl Reloader {
+    pub fn new(settings: SharedSettings, pinger: Pinger, monitor: Monitor) -> Self {
+        Reloader {
+            settings,
+            pinger,
+            monitor,
+        }
+    }
+
+    /// Re-reads the config file and environment, the current settings are kept on error.
+    ///
+    /// The resolver is recreated, dropping cached names, only if its upstreams or cache settings have changed,
+    /// the hosts file is always re-read.
+    /// The listen address can't be changed without a restart.
+    pub fn reload(&self) -> impl Future<Item = (), Error = Error> {
+        let current = self.settings.get();
+        let settings = match Settings::load(current.config.clone()) {
+            Ok(settings) => settings,
+            Err(error) => {
+                error!("Unable to reload settings: {}", error);
+                return boxed(future::err(Error::SettingsError { error }));
+            }
+        };
+
+        if settings.listen != current.listen {
+            warn!("Listen address is not changed on reload, restart is required");
+        }
+
+        let resolver = if settings.resolvers != current.resolvers
+            || settings.resolver_strategy != current.resolver_strategy
+            || settings.cache != current.cache
+        {
+            Either::A(
+                Resolver::new(
+                    settings.resolvers.to_vec(),
+                    settings.resolver_strategy,
+                    settings.hosts.clone(),
+                    settings.cache,
+                )
+                .map(Some),
+            )
+        } else {
+            Either::B(future::ok(None))
+        };
+
+        let shared = self.settings.clone();
+        let pinger = self.pinger.clone();
+        let monitor = self.monitor.clone();
+        let future = resolver
+            .map_err(|error| {
+                error!("Unable to reload settings: unable to create resolver");
+                Error::CreateResolverError { error }
+            })
+            .map(move |resolver| {
+                if let Some(resolver) = resolver {
+                    pinger.set_resolver(resolver);
+                } else if settings.hosts != current.hosts {
+                    pinger.set_hosts(settings.hosts.clone());
+                }
+                monitor.update(&settings);
+                info!("Reloaded settings: {}", settings);
+                shared.set(settings);
+            });
+        boxed(future)
+    }
+}
@@ -1,11 +1,23 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fmt;
+use std::fs;
+use std::io;
 use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
-use utils::Protocol;
+use toml;
+use trust_dns_resolver::Name;
+
+use exposition::Buckets;
+use hosts::Hosts;
+use http;
+use resolver::{CacheOptions, Strategy, Upstream};
+use socket::{Interface, Source};
+use utils::{Addresses, List, Mode, NameOrIpAddr, Protocol, ProtocolChoice};
 
 static ENV_PREFIX: &str = "PING_EXPORTER";
 static ENV_SEPARATOR: &str = "_";
@@ -22,11 +34,44 @@ pub struct Settings {
 
 impl fmt::Display for Settings {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if let Some(ref config) = self.config {
+            write!(f, "config file: {}, ", config.display())?;
+        }
         write!(f, "listen address: {}, ", self.listen)?;
         write!(f, "preferred protocol: {}, ", self.protocol)?;
-        match self.resolver {
-            Some(resolver) => write!(f, "resolver: {}, ", resolver)?,
-            None => write!(f, "resolver: system, ")?,
+        if self.resolvers.is_empty() {
+            write!(f, "resolver: system, ")?;
+        } else {
+            write!(f, "resolvers: {}, ", self.resolvers)?;
+            write!(f, "resolver strategy: {}, ", self.resolver_strategy)?;
+        }
+        if !self.allowed_resolvers.is_empty() {
+            write!(f, "allowed resolvers: {}, ", self.allowed_resolvers)?;
+        }
+        if let Some(ref hosts_file) = self.hosts_file {
+            write!(f, "hosts file: {}, ", hosts_file.display())?;
+        }
+        if !self.hosts.is_empty() {
+            write!(f, "static hosts: {}, ", self.hosts.len())?;
+        }
+        if let Some(addr) = self.source.addr {
+            write!(f, "source address: {}, ", addr)?;
+        }
+        if let Some(interface) = self.source.interface {
+            write!(f, "interface: {}, ", interface)?;
+        }
+        if !self.allowed_sources.is_empty() {
+            write!(f, "allowed source addresses: {}, ", self.allowed_sources)?;
+        }
+        if !self.allowed_interfaces.is_empty() {
+            write!(f, "allowed interfaces: {}, ", self.allowed_interfaces)?;
+        }
+        if let Some(ref cache) = self.cache {
+            write!(
+                f,
+                "resolver cache TTL: {}-{} s, negative TTL: {} s, size: {}, ",
+                cache.min_ttl, cache.max_ttl, cache.negative_ttl, cache.size
+            )?;
         }
         write!(f, "default number of ICMP packets: {}, ", self.count)?;
         write!(f, "maximum number of ICMP packets: {}, ", self.max_count)?;
@@ -43,45 +88,297 @@ impl fmt::Display for Settings {
         write!(f, "resolve timeout: {} ms, ", self.resolve_timeout)?;
         write!(
             f,
-            "maximum resolve timeout: {} ms.",
+            "maximum resolve timeout: {} ms, ",
             self.max_resolve_timeout
         )?;
+        write!(f, "maximum number of traceroute hops: {}, ", self.max_hops)?;
+        write!(f, "histogram buckets: {}, ", self.buckets)?;
+        write!(
+            f,
+            "maximum concurrent pings in a batch: {}, ",
+            self.ping_many_concurrency
+        )?;
+        write!(f, "maximum targets in a batch: {}, ", self.max_targets)?;
+        if !self.modules.is_empty() {
+            let names: Vec<&str> = self.modules.keys().map(String::as_str).collect();
+            write!(f, "modules: {}, ", names.join(","))?;
+        }
+        if self.targets.is_empty() {
+            write!(f, "monitored targets: none.")?;
+        } else {
+            write!(f, "monitored targets: {}, ", self.targets)?;
+            write!(f, "monitoring interval: {} ms, ", self.interval)?;
+            write!(f, "rolling window: {} pings.", self.window)?;
+        }
         Ok(())
     }
 }
 
 #[derive(Debug)]
 pub struct SettingsInner {
+    pub config: Option<PathBuf>,
     pub listen: SocketAddr,
     pub protocol: Protocol,
-    pub resolver: Option<IpAddr>,
+    pub resolvers: List<Upstream>,
+    pub resolver_strategy: Strategy,
+    pub allowed_resolvers: List<Upstream>,
+    pub hosts_file: Option<PathBuf>,
+    /// Names from the hosts file and the config file, the latter take precedence.
+    pub hosts: Hosts,
+    /// Missing if resolved names aren't cached.
+    pub cache: Option<CacheOptions>,
+    pub source: Source,
+    pub allowed_sources: List<IpAddr>,
+    pub allowed_interfaces: List<Interface>,
     pub count: usize,
     pub max_count: usize,
     pub ping_timeout: u64,
     pub max_ping_timeout: u64,
     pub resolve_timeout: u64,
     pub max_resolve_timeout: u64,
+    pub max_hops: u32,
+    pub buckets: Buckets,
+    pub ping_many_concurrency: usize,
+    pub max_targets: usize,
+    pub targets: List<NameOrIpAddr>,
+    pub interval: u64,
+    pub window: usize,
+    pub modules: BTreeMap<String, Module>,
+}
+
+/// Named set of `/ping` parameters, selected with `module=<name>`.
+///
+/// Query parameters take precedence over the module values,
+/// limits replace the global ones for requests using the module.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Module {
+    pub protocol: Option<ProtocolChoice>,
+    pub resolver: Option<Upstream>,
+    pub addresses: Option<Addresses>,
+    pub mode: Option<Mode>,
+    pub port: Option<u16>,
+    pub size: Option<usize>,
+    pub df: Option<bool>,
+    pub ttl: Option<u32>,
+    pub tos: Option<u8>,
+    pub dscp: Option<u8>,
+    pub source: Option<IpAddr>,
+    pub interface: Option<Interface>,
+    pub count: Option<usize>,
+    pub ping_timeout: Option<u64>,
+    pub resolve_timeout: Option<u64>,
+    pub buckets: Option<Buckets>,
+    pub ptr: Option<bool>,
+    pub cache: Option<bool>,
+    pub max_count: Option<usize>,
+    pub max_ping_timeout: Option<u64>,
+    pub max_resolve_timeout: Option<u64>,
+}
+
+/// Config file, keys are names of the environment variables without the prefix
+/// in lower case, e.g. `max_count` for `PING_EXPORTER_MAX_COUNT`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    listen: Option<SocketAddr>,
+    default_protocol: Option<Protocol>,
+    resolver: Option<List<Upstream>>,
+    resolver_strategy: Option<Strategy>,
+    allowed_resolvers: Option<List<Upstream>>,
+    hosts_file: Option<PathBuf>,
+    cache: Option<bool>,
+    cache_min_ttl: Option<u64>,
+    cache_max_ttl: Option<u64>,
+    cache_negative_ttl: Option<u64>,
+    cache_size: Option<usize>,
+    source: Option<IpAddr>,
+    interface: Option<Interface>,
+    allowed_sources: Option<List<IpAddr>>,
+    allowed_interfaces: Option<List<Interface>>,
+    default_count: Option<usize>,
+    max_count: Option<usize>,
+    default_ping_timeout: Option<u64>,
+    max_ping_timeout: Option<u64>,
+    default_resolve_timeout: Option<u64>,
+    max_resolve_timeout: Option<u64>,
+    max_hops: Option<u32>,
+    buckets: Option<Buckets>,
+    ping_many_concurrency: Option<usize>,
+    max_targets: Option<usize>,
+    targets: Option<List<NameOrIpAddr>>,
+    interval: Option<u64>,
+    window: Option<usize>,
+    #[serde(default)]
+    modules: BTreeMap<String, Module>,
+    #[serde(default)]
+    hosts: BTreeMap<String, List<IpAddr>>,
+}
+
+impl File {
+    fn read(path: &PathBuf) -> Result<Self, Error> {
+        let content = fs::read_to_string(path).map_err(|error| Error::ReadConfig {
+            path: path.display().to_string(),
+            error,
+        })?;
+        toml::from_str(&content).map_err(|error| Error::InvalidConfig {
+            path: path.display().to_string(),
+            error,
+        })
+    }
 }
 
 impl Settings {
-    pub fn from_env() -> Result<Self, Error> {
-        Ok(Self {
+    /// Reads the config file, if any, then environment variables, which take precedence.
+    ///
+    /// The path given on the command line takes precedence over `PING_EXPORTER_CONFIG`.
+    pub fn load(config: Option<PathBuf>) -> Result<Self, Error> {
+        let config = match config {
+            Some(config) => Some(config),
+            None => match get_env_::<String>("CONFIG") {
+                Ok(config) => Some(PathBuf::from(config)),
+                Err(Error::MissingEnvVar { .. }) => None,
+                Err(err) => return Err(err),
+            },
+        };
+        let file = match config {
+            Some(ref config) => File::read(config)?,
+            None => File::default(),
+        };
+
+        let hosts_file = match get_env_::<PathBuf>("HOSTS_FILE") {
+            Ok(hosts_file) => Some(hosts_file),
+            Err(Error::MissingEnvVar { .. }) => file.hosts_file,
+            Err(err) => return Err(err),
+        };
+        let mut hosts = match hosts_file {
+            Some(ref hosts_file) => read_hosts(hosts_file)?,
+            None => Hosts::default(),
+        };
+        for (name, addrs) in file.hosts {
+            let name = Name::from_str(&name).map_err(|_| Error::InvalidHostName { name })?;
+            hosts.insert(&name, addrs.to_vec());
+        }
+
+        let cache = if get_env_or("CACHE", file.cache.unwrap_or(false))? {
+            let cache = CacheOptions {
+                min_ttl: get_env_or("CACHE_MIN_TTL", file.cache_min_ttl.unwrap_or(0))?,
+                max_ttl: get_env_or("CACHE_MAX_TTL", file.cache_max_ttl.unwrap_or(3600))?,
+                negative_ttl: get_env_or(
+                    "CACHE_NEGATIVE_TTL",
+                    file.cache_negative_ttl.unwrap_or(30),
+                )?,
+                size: get_env_or("CACHE_SIZE", file.cache_size.unwrap_or(10000))?,
+            };
+            if cache.size == 0 {
+                return Err(Error::InvalidValue {
+                    name: env_name("CACHE_SIZE"),
+                    reason: "must be greater than zero",
+                });
+            }
+            if cache.min_ttl > cache.max_ttl {
+                return Err(Error::InvalidValue {
+                    name: env_name("CACHE_MIN_TTL"),
+                    reason: "must not be greater than the maximal TTL",
+                });
+            }
+            Some(cache)
+        } else {
+            None
+        };
+
+        let interval = get_env_or("INTERVAL", file.interval.unwrap_or(1000))?;
+        if interval == 0 {
+            return Err(Error::InvalidValue {
+                name: env_name("INTERVAL"),
+                reason: "must be greater than zero",
+            });
+        }
+
+        let source = Source {
+            addr: match get_env_::<IpAddr>("SOURCE") {
+                Ok(addr) => Some(addr),
+                Err(Error::MissingEnvVar { .. }) => file.source,
+                Err(err) => return Err(err),
+            },
+            interface: match get_env_::<Interface>("INTERFACE") {
+                Ok(interface) => Some(interface),
+                Err(Error::MissingEnvVar { .. }) => file.interface,
+                Err(err) => return Err(err),
+            },
+        };
+
+        let settings = Self {
             inner: Arc::new(SettingsInner {
-                listen: get_env_or("LISTEN", DEFAULT_LISTEN.clone())?,
-                protocol: get_env_or("DEFAULT_PROTOCOL", Protocol::V4)?,
-                resolver: match get_env_("RESOLVER") {
-                    Ok(resolver) => Some(resolver),
-                    Err(Error::MissingEnvVar { .. }) => None,
-                    Err(err) => return Err(err.into()),
-                },
-                count: get_env_or("DEFAULT_COUNT", 5)?,
-                max_count: get_env_or("MAX_COUNT", 30)?,
-                ping_timeout: get_env_or("DEFAULT_PING_TIMEOUT", 1000)?,
-                max_ping_timeout: get_env_or("MAX_PING_TIMEOUT", 10000)?,
-                resolve_timeout: get_env_or("DEFAULT_RESOLVE_TIMEOUT", 1000)?,
-                max_resolve_timeout: get_env_or("MAX_RESOLVE_TIMEOUT", 10000)?,
+                listen: get_env_or("LISTEN", file.listen.unwrap_or(*DEFAULT_LISTEN))?,
+                protocol: get_env_or(
+                    "DEFAULT_PROTOCOL",
+                    file.default_protocol.unwrap_or(Protocol::V4),
+                )?,
+                resolvers: get_env_or("RESOLVER", file.resolver.unwrap_or_default())?,
+                resolver_strategy: get_env_or(
+                    "RESOLVER_STRATEGY",
+                    file.resolver_strategy.unwrap_or(Strategy::Failover),
+                )?,
+                allowed_resolvers: get_env_or(
+                    "ALLOWED_RESOLVERS",
+                    file.allowed_resolvers.unwrap_or_default(),
+                )?,
+                hosts_file,
+                hosts,
+                cache,
+                source,
+                allowed_sources: get_env_or(
+                    "ALLOWED_SOURCES",
+                    file.allowed_sources.unwrap_or_default(),
+                )?,
+                allowed_interfaces: get_env_or(
+                    "ALLOWED_INTERFACES",
+                    file.allowed_interfaces.unwrap_or_default(),
+                )?,
+                count: get_env_or("DEFAULT_COUNT", file.default_count.unwrap_or(5))?,
+                max_count: get_env_or("MAX_COUNT", file.max_count.unwrap_or(30))?,
+                ping_timeout: get_env_or(
+                    "DEFAULT_PING_TIMEOUT",
+                    file.default_ping_timeout.unwrap_or(1000),
+                )?,
+                max_ping_timeout: get_env_or(
+                    "MAX_PING_TIMEOUT",
+                    file.max_ping_timeout.unwrap_or(10000),
+                )?,
+                resolve_timeout: get_env_or(
+                    "DEFAULT_RESOLVE_TIMEOUT",
+                    file.default_resolve_timeout.unwrap_or(1000),
+                )?,
+                max_resolve_timeout: get_env_or(
+                    "MAX_RESOLVE_TIMEOUT",
+                    file.max_resolve_timeout.unwrap_or(10000),
+                )?,
+                max_hops: get_env_or("MAX_HOPS", file.max_hops.unwrap_or(30))?,
+                buckets: get_env_or("BUCKETS", file.buckets.unwrap_or_default())?,
+                ping_many_concurrency: get_env_or(
+                    "PING_MANY_CONCURRENCY",
+                    file.ping_many_concurrency.unwrap_or(16),
+                )?,
+                max_targets: get_env_or("MAX_TARGETS", file.max_targets.unwrap_or(100))?,
+                targets: get_env_or("TARGETS", file.targets.unwrap_or_default())?,
+                interval,
+                window: get_env_or("WINDOW", file.window.unwrap_or(60))?,
+                modules: file.modules,
+                config,
             }),
-        })
+        };
+
+        for (name, module) in &settings.modules {
+            if let Some(reason) = http::check_module(module, &settings) {
+                return Err(Error::InvalidValue {
+                    name: format!("module {}", name),
+                    reason,
+                });
+            }
+        }
+
+        Ok(settings)
     }
 }
 
@@ -93,12 +390,57 @@ impl Deref for Settings {
     }
 }
 
+/// Current settings shared between the server and background tasks,
+/// replaced as a whole on reload.
+#[derive(Debug, Clone)]
+pub struct SharedSettings {
+    inner: Arc<RwLock<Settings>>,
+}
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> Self {
+        SharedSettings {
+            inner: Arc::new(RwLock::new(settings)),
+        }
+    }
+
+    pub fn get(&self) -> Settings {
+        self.inner.read().expect("settings").clone()
+    }
+
+    pub fn set(&self, settings: Settings) {
+        *self.inner.write().expect("settings") = settings;
+    }
+}
+
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "missing environment variable: {}", name)]
     MissingEnvVar { name: String },
     #[fail(display = "invalid environment variable type: {}", name)]
     InvalidVariableType { name: String },
+    #[fail(display = "unable to read config file {}: {}", path, error)]
+    ReadConfig { path: String, error: io::Error },
+    #[fail(display = "invalid config file {}: {}", path, error)]
+    InvalidConfig {
+        path: String,
+        error: toml::de::Error,
+    },
+    #[fail(display = "unable to read hosts file {}: {}", path, error)]
+    ReadHostsFile { path: String, error: io::Error },
+    #[fail(display = "invalid host name: {}", name)]
+    InvalidHostName { name: String },
+    #[fail(display = "invalid value of {}: {}", name, reason)]
+    InvalidValue { name: String, reason: &'static str },
+}
+
+fn read_hosts(path: &PathBuf) -> Result<Hosts, Error> {
+    fs::read_to_string(path)
+        .map(|content| Hosts::parse(&content))
+        .map_err(|error| Error::ReadHostsFile {
+            path: path.display().to_string(),
+            error,
+        })
 }
 
 fn get_env_or<T: FromStr>(name: &str, default: T) -> Result<T, Error> {
@@ -109,8 +451,12 @@ fn get_env_or<T: FromStr>(name: &str, default: T) -> Result<T, Error> {
     }
 }
 
+fn env_name(name: &str) -> String {
+    format!("{}{}{}", ENV_PREFIX, ENV_SEPARATOR, name.to_uppercase())
+}
+
 fn get_env_<T: FromStr>(name: &str) -> Result<T, Error> {
-    let env_var_name = format!("{}{}{}", ENV_PREFIX, ENV_SEPARATOR, name.to_uppercase());
+    let env_var_name = env_name(name);
 
     let string = env::var(&env_var_name).map_err(|_| Error::MissingEnvVar {
         name: env_var_name.clone(),
@@ -120,10 +466,69 @@ fn get_env_<T: FromStr>(name: &str) -> Result<T, Error> {
 
 #[cfg(test)]
 mod tests {
-    use super::Settings;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use toml;
+
+    use super::{File, Settings};
 
     #[test]
     fn test_valid_default_settings() {
-        assert!(Settings::from_env().is_ok());
+        assert!(Settings::load(None).is_ok());
+    }
+
+    #[test]
+    fn test_config_file() {
+        let file: File = toml::from_str(
+            r#"
+            max_count = 10
+            buckets = [0.01, 0.1, 1]
+            targets = ["example.com", "127.0.0.1"]
+            "#,
+        )
+        .expect("config");
+        assert_eq!(file.max_count, Some(10));
+        assert_eq!(file.buckets.map(|b| b.len()), Some(3));
+        assert_eq!(file.targets.map(|t| t.len()), Some(2));
+
+        let file: File = toml::from_str(
+            r#"
+            [modules.tcp_https]
+            mode = "tcp"
+            port = 443
+            max_count = 3
+            "#,
+        )
+        .expect("config");
+        let module = &file.modules["tcp_https"];
+        assert_eq!(module.port, Some(443));
+        assert_eq!(module.max_count, Some(3));
+        assert!(toml::from_str::<File>("[modules.icmp]\ncuont = 3").is_err());
+
+        let file: File = toml::from_str(
+            r#"
+            [hosts]
+            "db.internal" = ["10.0.0.1", "fd00::1"]
+            "#,
+        )
+        .expect("config");
+        assert_eq!(file.hosts["db.internal"].len(), 2);
+
+        let err = toml::from_str::<File>("max_cuont = 10").expect_err("unknown key");
+        assert!(err.to_string().contains("unknown field `max_cuont`"));
+    }
+
+    #[test]
+    fn test_invalid_module() {
+        let config = env::temp_dir().join(format!("ping-exporter-{}.toml", process::id()));
+        fs::write(&config, "[modules.tcp]\nmode = \"tcp\"\n").expect("config");
+        let err = Settings::load(Some(config.clone())).expect_err("invalid module");
+        fs::remove_file(&config).expect("remove config");
+        assert_eq!(
+            err.to_string(),
+            "invalid value of module tcp: Port is required in tcp and udp modes"
+        );
     }
 }
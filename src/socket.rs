@@ -0,0 +1,155 @@
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+
+use libc::{self, c_int};
+use serde::{de::Error as SerdeDeError, Deserialize, Deserializer};
+use socket2::{SockAddr, Socket};
+
+use utils::Protocol;
+
+/// Name of a network interface, kept inline so probe options stay `Copy`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Interface {
+    name: [u8; libc::IFNAMSIZ],
+    len: usize,
+}
+
+impl Interface {
+    fn as_bytes(&self) -> &[u8] {
+        &self.name[..self.len]
+    }
+}
+
+impl fmt::Debug for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Interface({})", self)
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(self.as_bytes()))
+    }
+}
+
+impl FromStr for Interface {
+    type Err = String;
+
+    /// Follows the rules of the kernel: at most 15 bytes, no slashes, colons or whitespace.
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        let invalid = s.is_empty()
+            || s.len() >= libc::IFNAMSIZ
+            || s == "."
+            || s == ".."
+            || s.chars()
+                .any(|c| c == '/' || c == ':' || c == '\0' || c.is_whitespace());
+        if invalid {
+            return Err(format!("'{}' is not valid interface name", s));
+        }
+
+        let mut name = [0; libc::IFNAMSIZ];
+        name[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Interface { name, len: s.len() })
+    }
+}
+
+impl<'de> Deserialize<'de> for Interface {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Interface::from_str(String::deserialize(deserializer)?.as_str()).map_err(D::Error::custom)
+    }
+}
+
+/// Local address and interface probes are sent from, the system picks them if missing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Source {
+    pub addr: Option<IpAddr>,
+    pub interface: Option<Interface>,
+}
+
+impl Source {
+    /// Source actually used over the protocol: an address of the other one is skipped,
+    /// so only the interface is left.
+    pub fn for_protocol(&self, protocol: Protocol) -> Self {
+        Source {
+            addr: self.addr.filter(|addr| Protocol::of(addr) == protocol),
+            ..*self
+        }
+    }
+
+    /// Binds the socket before anything is sent to `dest`.
+    pub fn bind(&self, socket: &Socket, dest: IpAddr) -> io::Result<()> {
+        let source = self.for_protocol(Protocol::of(&dest));
+        if let Some(ref interface) = source.interface {
+            let name = CString::new(interface.as_bytes())?;
+            socket.bind_device(Some(&name))?;
+        }
+        match source.addr {
+            Some(addr) => socket.bind(&SockAddr::from(SocketAddr::new(addr, 0))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sets socket options missing from `socket2`.
+pub fn set_option(socket: &Socket, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const c_int as *const libc::c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use std::net::IpAddr;
+
+    use super::{Interface, Source};
+    use utils::Protocol;
+
+    #[test]
+    fn test_interface() {
+        let interface = Interface::from_str("wg0").expect("interface");
+        assert_eq!(interface.to_string(), "wg0");
+        assert!(Interface::from_str("eth0.100").is_ok());
+        assert!(Interface::from_str("").is_err());
+        assert!(Interface::from_str("a-very-long-name").is_err());
+        assert!(Interface::from_str("eth0:1").is_err());
+        assert!(Interface::from_str("..").is_err());
+    }
+
+    #[test]
+    fn test_source_for_protocol() {
+        let interface = Interface::from_str("wg0").ok();
+        let source = Source {
+            addr: Some(IpAddr::from([192, 0, 2, 1])),
+            interface,
+        };
+        assert_eq!(source.for_protocol(Protocol::V4), source);
+        assert_eq!(
+            source.for_protocol(Protocol::V6),
+            Source {
+                addr: None,
+                interface
+            }
+        );
+    }
+}
@@ -0,0 +1,64 @@
+/// Summary of the successful round-trip times in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RttStats {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub stddev: f64,
+    pub jitter: f64,
+}
+
+/// Calculates RTT statistics from replies in the order they were sent,
+/// returns `None` if there are no replies at all.
+///
+/// Jitter is the interarrival jitter estimator from RFC 3550 (section 6.4.1),
+/// calculated over consecutive replies, lost packets are skipped.
+pub fn rtt_stats(pings: &[Option<f64>]) -> Option<RttStats> {
+    let times: Vec<f64> = pings.iter().filter_map(|&time| time).collect();
+    if times.is_empty() {
+        return None;
+    }
+
+    let min = times.iter().cloned().fold(::std::f64::INFINITY, f64::min);
+    let max = times
+        .iter()
+        .cloned()
+        .fold(::std::f64::NEG_INFINITY, f64::max);
+    let avg = times.iter().sum::<f64>() / times.len() as f64;
+    let variance = times
+        .iter()
+        .map(|time| (time - avg) * (time - avg))
+        .sum::<f64>()
+        / times.len() as f64;
+
+    let jitter = times.windows(2).fold(0.0, |jitter, pair| {
+        let delta = (pair[1] - pair[0]).abs();
+        jitter + (delta - jitter) / 16.0
+    });
+
+    Some(RttStats {
+        min,
+        max,
+        avg,
+        stddev: variance.sqrt(),
+        jitter,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rtt_stats;
+
+    #[test]
+    fn test_rtt_stats() {
+        assert_eq!(rtt_stats(&[None, None]), None);
+
+        let stats = rtt_stats(&[Some(0.01), None, Some(0.03), Some(0.02)]).expect("stats");
+        assert_eq!(stats.min, 0.01);
+        assert_eq!(stats.max, 0.03);
+        assert!((stats.avg - 0.02).abs() < 1e-12);
+        assert!((stats.stddev - 0.0081649658).abs() < 1e-9);
+        // |0.03 - 0.01| / 16, then + (0.01 - 0.00125) / 16
+        assert!((stats.jitter - 0.001796875).abs() < 1e-12);
+    }
+}
@@ -1,18 +1,31 @@
 use std::fmt;
+use std::marker::PhantomData;
 use std::net::IpAddr;
+use std::ops::Deref;
 use std::str::FromStr;
 use std::sync::Arc;
 
-use futures::Future;
-use serde::{de::Error as SerdeDeError, Deserialize, Deserializer};
+use futures::{Future, Stream};
+use rand::{seq::SliceRandom, thread_rng};
+use serde::de::{Error as SerdeDeError, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
 use trust_dns_resolver::Name;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Protocol {
     V4,
     V6,
 }
 
+impl Protocol {
+    pub fn of(addr: &IpAddr) -> Self {
+        match addr {
+            &IpAddr::V4(_) => Protocol::V4,
+            &IpAddr::V6(_) => Protocol::V6,
+        }
+    }
+}
+
 impl fmt::Display for Protocol {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -43,12 +56,176 @@ impl<'de> Deserialize<'de> for Protocol {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Protocol requested for a name target: `Both` pings over IPv4 and IPv6 at the same time,
+/// `Auto` picks one like a Happy Eyeballs (RFC 8305) client would.
+#[derive(Debug, Clone, Copy)]
+pub enum ProtocolChoice {
+    Single(Protocol),
+    Both,
+    Auto,
+}
+
+impl fmt::Display for ProtocolChoice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ProtocolChoice::Single(ref protocol) => protocol.fmt(f),
+            &ProtocolChoice::Both => write!(f, "both"),
+            &ProtocolChoice::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+impl FromStr for ProtocolChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        match s {
+            "both" => Ok(ProtocolChoice::Both),
+            "auto" => Ok(ProtocolChoice::Auto),
+            other => Protocol::from_str(other)
+                .map(ProtocolChoice::Single)
+                .map_err(|_| {
+                    format!(
+                        "'{}' is not valid protocol, use v4, v6, both or auto",
+                        other
+                    )
+                }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ProtocolChoice {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        ProtocolChoice::from_str(String::deserialize(deserializer)?.as_str())
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Icmp,
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Mode::Icmp => write!(f, "icmp"),
+            &Mode::Tcp => write!(f, "tcp"),
+            &Mode::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        match s {
+            "icmp" => Ok(Mode::Icmp),
+            "tcp" => Ok(Mode::Tcp),
+            "udp" => Ok(Mode::Udp),
+            other => Err(format!(
+                "'{}' is not valid mode, use icmp, tcp or udp",
+                other
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Mode::from_str(String::deserialize(deserializer)?.as_str()).map_err(D::Error::custom)
+    }
+}
+
+/// Which of the resolved addresses are pinged.
+#[derive(Debug, Clone, Copy)]
+pub enum Addresses {
+    All,
+    First,
+    Random,
+}
+
+impl Addresses {
+    /// `First` is the lowest address, so the choice doesn't depend on the order of records.
+    pub fn select(self, mut addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+        addrs.sort();
+        addrs.dedup();
+        match self {
+            Addresses::All => addrs,
+            Addresses::First => addrs.into_iter().take(1).collect(),
+            Addresses::Random => addrs
+                .choose(&mut thread_rng())
+                .cloned()
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for Addresses {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Addresses::All => write!(f, "all"),
+            &Addresses::First => write!(f, "first"),
+            &Addresses::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl FromStr for Addresses {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        match s {
+            "all" => Ok(Addresses::All),
+            "first" => Ok(Addresses::First),
+            "random" => Ok(Addresses::Random),
+            other => Err(format!(
+                "'{}' is not valid addresses policy, use all, first or random",
+                other
+            )),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Addresses {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Addresses::from_str(String::deserialize(deserializer)?.as_str()).map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum NameOrIpAddr {
     Name(Arc<Name>),
     IpAddr(IpAddr),
 }
 
+impl NameOrIpAddr {
+    /// IP addresses are always pinged over their own protocol.
+    pub fn protocol(&self, default: Protocol) -> Protocol {
+        self.ip_protocol().unwrap_or(default)
+    }
+
+    pub fn ip_protocol(&self) -> Option<Protocol> {
+        match self {
+            &NameOrIpAddr::IpAddr(ref addr) => Some(Protocol::of(addr)),
+            &NameOrIpAddr::Name(_) => None,
+        }
+    }
+}
+
 impl fmt::Display for NameOrIpAddr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -78,8 +255,126 @@ impl<'de> Deserialize<'de> for NameOrIpAddr {
     }
 }
 
+/// Comma separated list of values, empty string is an empty list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct List<T>(Vec<T>);
+
+impl<T> Default for List<T> {
+    fn default() -> Self {
+        List(Vec::new())
+    }
+}
+
+impl<T> Deref for List<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &<Self as Deref>::Target {
+        &self.0
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, value) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: FromStr> FromStr for List<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, <Self as FromStr>::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(T::from_str)
+            .collect::<Result<Vec<T>, T::Err>>()
+            .map(List)
+    }
+}
+
+struct ListVisitor<T>(PhantomData<T>);
+
+impl<'de, T: FromStr> Visitor<'de> for ListVisitor<T>
+where
+    T::Err: fmt::Display,
+{
+    type Value = List<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a comma separated string or an array of strings")
+    }
+
+    fn visit_str<E: SerdeDeError>(self, value: &str) -> Result<Self::Value, E> {
+        List::from_str(value).map_err(E::custom)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = Vec::new();
+        while let Some(value) = seq.next_element::<String>()? {
+            values.push(T::from_str(value.trim()).map_err(A::Error::custom)?);
+        }
+        Ok(List(values))
+    }
+}
+
+/// Accepts both a comma separated string (query parameters and environment)
+/// and an array of strings (config file).
+impl<'de, T: FromStr> Deserialize<'de> for List<T>
+where
+    T::Err: fmt::Display,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ListVisitor(PhantomData))
+    }
+}
+
+/// Names are compared case insensitively, but `example.com` and `example.com.` are hashed differently,
+/// so names used as keys are made fully qualified.
+pub fn fqdn(name: &Name) -> Name {
+    let mut name = name.clone();
+    name.set_fqdn(true);
+    name
+}
+
 pub fn boxed<F: Future<Item = I, Error = E> + Send + 'static, I, E>(
     future: F,
 ) -> Box<Future<Item = I, Error = E> + Send> {
     Box::new(future)
 }
+
+pub fn boxed_stream<S: Stream<Item = I, Error = E> + Send + 'static, I, E>(
+    stream: S,
+) -> Box<Stream<Item = I, Error = E> + Send> {
+    Box::new(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    use super::Addresses;
+
+    #[test]
+    fn test_select_addresses() {
+        let addrs: Vec<IpAddr> = ["192.0.2.2", "192.0.2.1", "192.0.2.2"]
+            .iter()
+            .map(|addr| IpAddr::from_str(addr).expect("addr"))
+            .collect();
+
+        let all = Addresses::All.select(addrs.clone());
+        assert_eq!(all, vec![addrs[1], addrs[0]]);
+        assert_eq!(Addresses::First.select(addrs.clone()), vec![addrs[1]]);
+        assert_eq!(Addresses::Random.select(addrs).len(), 1);
+        assert!(Addresses::Random.select(Vec::new()).is_empty());
+    }
+}